@@ -0,0 +1,349 @@
+use std::fs::{read, read_dir, read_to_string, symlink_metadata, write};
+use std::io::Error as ioError;
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Map, Value};
+
+use Result;
+
+/// Where a bundle records the files that embed its original prefix, relative to the prefix.  Not
+/// in conda-meta, where conda reads every JSON file as a package record.
+pub const BUNDLE_METADATA_PATH: &str = "conda-lockfile-bundle.json";
+
+/// How a file embeds the prefix.  Text files can be rewritten freely; binaries hold
+/// NUL-terminated strings whose length can't change, so they are padded instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrefixMode {
+    Text,
+    Binary,
+}
+
+/// A file within the env that contains the prefix it was built at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrefixFile {
+    pub path: PathBuf,
+    pub mode: PrefixMode,
+}
+
+/// Find every regular file under `prefix` that contains the prefix path.  Like conda-pack,
+/// compiled python files are left alone: the paths in them only show up in tracebacks, and
+/// padding their length-prefixed strings would corrupt them.
+pub fn find_prefix_files(prefix: &Path) -> Result<Vec<PrefixFile>> {
+    let finder = Finder::new(prefix.to_str().unwrap().as_bytes());
+    let mut files = Vec::new();
+    let mut dirs = vec![prefix.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(&dir)? {
+            let path = entry?.path();
+            // Symlinks are archived as symlinks; their targets are checked on their own.
+            let file_type = symlink_metadata(&path)?.file_type();
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if !file_type.is_file() || path.extension().is_some_and(|ext| ext == "pyc") {
+                continue;
+            }
+            let data = read(&path)?;
+            if finder.find(&data, 0).is_none() {
+                continue;
+            }
+            let mode = if data.contains(&0) {
+                PrefixMode::Binary
+            } else {
+                PrefixMode::Text
+            };
+            files.push(PrefixFile {
+                path: path.strip_prefix(prefix)?.to_path_buf(),
+                mode,
+            });
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Record the files to fix up when the bundle is unpacked somewhere else.
+pub fn write_metadata(prefix: &Path, files: &[PrefixFile]) -> Result<()> {
+    let files: Vec<Value> = files
+        .iter()
+        .map(|file| {
+            let mode = match file.mode {
+                PrefixMode::Text => "text",
+                PrefixMode::Binary => "binary",
+            };
+            let mut entry = Map::new();
+            entry.insert("path".to_string(), Value::from(file.path.to_str().unwrap()));
+            entry.insert("mode".to_string(), Value::from(mode));
+            Value::Object(entry)
+        })
+        .collect();
+    let mut metadata = Map::new();
+    metadata.insert("prefix".to_string(), Value::from(prefix.to_str().unwrap()));
+    // Binaries keep the room the build prefix took, however often the bundle moves.
+    metadata.insert(
+        "max_prefix_len".to_string(),
+        Value::from(prefix.to_str().unwrap().len()),
+    );
+    metadata.insert("files".to_string(), Value::Array(files));
+    let metadata = Value::Object(metadata);
+    write(
+        prefix.join(BUNDLE_METADATA_PATH),
+        serde_json::to_string_pretty(&metadata)?,
+    )?;
+    Ok(())
+}
+
+/// Rewrite an unpacked bundle's files to refer to the prefix it was unpacked at.
+pub fn relocate(prefix: &Path) -> Result<()> {
+    let metadata_path = prefix.join(BUNDLE_METADATA_PATH);
+    let mut metadata: Value = match read_to_string(&metadata_path) {
+        Ok(data) => serde_json::from_str(&data)?,
+        Err(_) => {
+            let msg = format!("{} is not a conda-lockfile bundle", prefix.display());
+            return Err(ioError::other(msg).into());
+        }
+    };
+    let old_prefix = metadata["prefix"].as_str().unwrap_or("").to_string();
+    let new_prefix = prefix.to_str().unwrap();
+    if old_prefix.is_empty() || old_prefix == new_prefix {
+        return Ok(());
+    }
+    let max_len = metadata["max_prefix_len"]
+        .as_u64()
+        .map_or(old_prefix.len(), |len| len as usize);
+    for file in metadata["files"].as_array().unwrap_or(&Vec::new()) {
+        let (path, mode) = match (file["path"].as_str(), file["mode"].as_str()) {
+            (Some(path), Some(mode)) => (prefix.join(path), mode),
+            _ => continue,
+        };
+        debug!("Relocating {}", path.display());
+        let data = read(&path)?;
+        let data = match mode {
+            "binary" => {
+                replace_prefix_binary(&data, old_prefix.as_bytes(), new_prefix.as_bytes(), max_len)?
+            }
+            _ => replace_prefix_text(&data, old_prefix.as_bytes(), new_prefix.as_bytes()),
+        };
+        write(&path, data)?;
+    }
+    // The bundle can be moved again later.
+    metadata["prefix"] = Value::String(new_prefix.to_string());
+    write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+    Ok(())
+}
+
+// Boyer-Moore-Horspool search: envs hold gigabytes of files and the prefix is long, so most of
+// each file can be skipped over rather than compared byte by byte.
+struct Finder<'a> {
+    needle: &'a [u8],
+    skip: [usize; 256],
+}
+
+impl<'a> Finder<'a> {
+    fn new(needle: &'a [u8]) -> Finder<'a> {
+        let mut skip = [needle.len(); 256];
+        if let Some((_, init)) = needle.split_last() {
+            for (idx, &byte) in init.iter().enumerate() {
+                skip[byte as usize] = needle.len() - 1 - idx;
+            }
+        }
+        Finder { needle, skip }
+    }
+
+    fn find(&self, data: &[u8], start: usize) -> Option<usize> {
+        let len = self.needle.len();
+        if len == 0 {
+            return None;
+        }
+        let last = self.needle[len - 1];
+        let mut idx = start;
+        while idx + len <= data.len() {
+            let byte = data[idx + len - 1];
+            if byte == last && data[idx..idx + len - 1] == self.needle[..len - 1] {
+                return Some(idx);
+            }
+            idx += self.skip[byte as usize];
+        }
+        None
+    }
+}
+
+fn replace_prefix_text(data: &[u8], old: &[u8], new: &[u8]) -> Vec<u8> {
+    replace_with(&Finder::new(old), data, new)
+}
+
+fn replace_with(finder: &Finder, data: &[u8], new: &[u8]) -> Vec<u8> {
+    let old = finder.needle;
+    let mut replaced = Vec::with_capacity(data.len());
+    let mut start = 0;
+    while let Some(idx) = finder.find(data, start) {
+        replaced.extend_from_slice(&data[start..idx]);
+        replaced.extend_from_slice(new);
+        start = idx + old.len();
+    }
+    replaced.extend_from_slice(&data[start..]);
+    replaced
+}
+
+// Like conda, replace the prefix within each NUL-terminated string and pad the string back to
+// its original length with NULs, so offsets in the binary stay valid.  `max_len` is the length
+// of the prefix the binary was built with: a bundle that was already moved to a shorter prefix
+// still has that much room, in the padding after each string.
+fn replace_prefix_binary(data: &[u8], old: &[u8], new: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    if new.len() > max_len {
+        let msg = format!(
+            "Binary files can only be relocated to a prefix of at most {} characters",
+            max_len
+        );
+        return Err(ioError::other(msg).into());
+    }
+    let finder = Finder::new(old);
+    let mut replaced = Vec::with_capacity(data.len());
+    let mut start = 0;
+    while let Some(idx) = finder.find(data, start) {
+        let end = data[idx..]
+            .iter()
+            .position(|&byte| byte == 0)
+            .map_or(data.len(), |offset| idx + offset);
+        let string = replace_with(&finder, &data[idx..end], new);
+        let mut occurrences = 0;
+        let mut next = idx;
+        while let Some(found) = finder.find(&data[..end], next) {
+            occurrences += 1;
+            next = found + old.len();
+        }
+        let slot_end = end + occurrences * (max_len - old.len());
+        if slot_end > data.len() || data[end..slot_end].iter().any(|&byte| byte != 0) {
+            let msg = "Binary file is missing the padding left by an earlier relocation";
+            return Err(ioError::other(msg).into());
+        }
+        replaced.extend_from_slice(&data[start..idx]);
+        replaced.extend_from_slice(&string);
+        replaced.resize(replaced.len() + (slot_end - idx) - string.len(), 0);
+        start = slot_end;
+    }
+    replaced.extend_from_slice(&data[start..]);
+    Ok(replaced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, File};
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn text_replacement() {
+        assert_eq!(
+            replace_prefix_text(
+                b"#!/tmp/long/env/bin/python\n/tmp/long/env",
+                b"/tmp/long/env",
+                b"/opt/env"
+            ),
+            b"#!/opt/env/bin/python\n/opt/env"
+        );
+    }
+
+    #[test]
+    fn prefix_search() {
+        let finder = Finder::new(b"/env/env");
+        assert_eq!(finder.find(b"/env/env", 0), Some(0));
+        assert_eq!(finder.find(b"x/env/env/env", 0), Some(1));
+        assert_eq!(finder.find(b"x/env/env/env", 2), Some(5));
+        assert_eq!(finder.find(b"/env/en", 0), None);
+        assert_eq!(finder.find(b"/env/eNv/env/env", 0), Some(8));
+        assert_eq!(Finder::new(b"").find(b"abc", 0), None);
+    }
+
+    #[test]
+    fn binary_replacement() {
+        let data = b"\x7fELF\0/tmp/long/env/lib:/tmp/long/env/lib64\0rest";
+        let replaced = replace_prefix_binary(data, b"/tmp/long/env", b"/opt/env", 13).unwrap();
+        assert_eq!(replaced.len(), data.len());
+        assert_eq!(
+            &replaced[..],
+            &b"\x7fELF\0/opt/env/lib:/opt/env/lib64\0\0\0\0\0\0\0\0\0\0\0rest"[..]
+        );
+        assert!(
+            replace_prefix_binary(data, b"/tmp/long/env", b"/a/much/longer/prefix", 13).is_err()
+        );
+
+        // Moving again may use the padding left by the first move, but no more.
+        let moved = replace_prefix_binary(&replaced, b"/opt/env", b"/opt/env/v2", 13).unwrap();
+        assert_eq!(
+            &moved[..],
+            &b"\x7fELF\0/opt/env/v2/lib:/opt/env/v2/lib64\0\0\0\0\0rest"[..]
+        );
+        assert!(replace_prefix_binary(&replaced, b"/opt/env", b"/tmp/longer/env", 13).is_err());
+    }
+
+    #[test]
+    fn relocate_bundle() {
+        let dir = tempdir().unwrap();
+        let old_prefix = dir.path().join("build_placeholder_prefix");
+        create_dir_all(old_prefix.join("conda-meta")).unwrap();
+        create_dir_all(old_prefix.join("bin")).unwrap();
+        let old = old_prefix.to_str().unwrap();
+        File::create(old_prefix.join("bin").join("tool"))
+            .unwrap()
+            .write_all(format!("#!{}/bin/python\n", old).as_bytes())
+            .unwrap();
+        File::create(old_prefix.join("lib.so"))
+            .unwrap()
+            .write_all(format!("\0{}/lib\0", old).as_bytes())
+            .unwrap();
+        File::create(old_prefix.join("README")).unwrap();
+        File::create(old_prefix.join("tool.pyc"))
+            .unwrap()
+            .write_all(format!("\0\0{}/tool.py\0", old).as_bytes())
+            .unwrap();
+
+        let files = find_prefix_files(&old_prefix).unwrap();
+        assert_eq!(
+            files,
+            [
+                PrefixFile {
+                    path: PathBuf::from("bin/tool"),
+                    mode: PrefixMode::Text,
+                },
+                PrefixFile {
+                    path: PathBuf::from("lib.so"),
+                    mode: PrefixMode::Binary,
+                },
+            ]
+        );
+        write_metadata(&old_prefix, &files).unwrap();
+        assert!(old_prefix.join(BUNDLE_METADATA_PATH).is_file());
+        assert_eq!(read_dir(old_prefix.join("conda-meta")).unwrap().count(), 0);
+
+        // Unpacking is simulated by moving the prefix.
+        let new_prefix = dir.path().join("env");
+        ::std::fs::rename(&old_prefix, &new_prefix).unwrap();
+        relocate(&new_prefix).unwrap();
+        let new = new_prefix.to_str().unwrap();
+        assert_eq!(
+            read_to_string(new_prefix.join("bin").join("tool")).unwrap(),
+            format!("#!{}/bin/python\n", new)
+        );
+        let lib = read(new_prefix.join("lib.so")).unwrap();
+        assert!(lib.starts_with(format!("\0{}/lib\0", new).as_bytes()));
+        assert_eq!(lib.len(), old.len() + 6);
+
+        // A second move may be to a longer prefix, as long as it fits the build prefix.
+        let moved_prefix = dir.path().join("moved_env");
+        ::std::fs::rename(&new_prefix, &moved_prefix).unwrap();
+        relocate(&moved_prefix).unwrap();
+        let moved = moved_prefix.to_str().unwrap();
+        let lib = read(moved_prefix.join("lib.so")).unwrap();
+        assert!(lib.starts_with(format!("\0{}/lib\0", moved).as_bytes()));
+        assert_eq!(lib.len(), old.len() + 6);
+        assert_eq!(
+            read_to_string(moved_prefix.join("bin").join("tool")).unwrap(),
+            format!("#!{}/bin/python\n", moved)
+        );
+    }
+}
@@ -0,0 +1,468 @@
+use std::env;
+use std::error::Error;
+use std::fs::read_to_string;
+use std::io::Error as ioError;
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use sha1;
+use sha2::{Digest, Sha256};
+use toml::Value;
+
+use channels::{self, AllowedChannels};
+use policy::Policy;
+use Result;
+
+/// The project config file, looked for in the cwd and each of its parents.
+pub const CONFIG_FILE: &str = "conda-lockfile.toml";
+/// Python projects can keep the settings in a `[tool.conda-lockfile]` table instead.
+const PYPROJECT_FILE: &str = "pyproject.toml";
+
+const PLATFORMS: &[&str] = &["Darwin", "Linux"];
+const CHANNEL_PRIORITIES: &[&str] = &["strict", "flexible", "disabled"];
+
+/// How lockfiles are named by default, relative to their depfile's directory.  `{stem}` and
+/// `{name}` are the depfile's name without and with its extension.
+pub const LOCKFILE_TEMPLATE: &str = "{stem}.{platform}.lock.yml";
+const TEMPLATE_PLACEHOLDERS: &[&str] = &["{stem}", "{name}", "{platform}"];
+
+/// How the depfile hash in a lockfile's `ENVHASH` line is computed.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// The algorithm a recorded hash was made with.  sha256 hashes are prefixed with `sha256:`;
+    /// bare ones are sha1, as in lockfiles written before the algorithm was configurable.
+    pub fn of(hash: &str) -> HashAlgorithm {
+        if hash.starts_with("sha256:") {
+            HashAlgorithm::Sha256
+        } else {
+            HashAlgorithm::Sha1
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> String {
+        match self {
+            HashAlgorithm::Sha1 => {
+                let mut m = sha1::Sha1::new();
+                m.update(data);
+                m.digest().to_string()
+            }
+            HashAlgorithm::Sha256 => format!("sha256:{:x}", Sha256::digest(data)),
+        }
+    }
+}
+
+/// Settings for the containers conda-lockfile builds or generates.
+#[derive(Debug, Default, PartialEq)]
+pub struct ContainerConfig {
+    /// Image lockfiles for other platforms are frozen in.
+    pub image: Option<String>,
+    /// Miniconda installer run in that image.
+    pub installer: Option<String>,
+    /// Defaults for `dockerize`.
+    pub prefix: Option<String>,
+    pub base_image: Option<String>,
+    pub micromamba_version: Option<String>,
+}
+
+/// Project-wide defaults.  Command line flags take precedence over everything here.
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// The file the settings were read from, if one was found.
+    pub path: Option<PathBuf>,
+    /// Relative to the cwd, though it's written relative to the config file.
+    pub depfile: Option<String>,
+    /// Lockfile naming template, as for `LOCKFILE_TEMPLATE`.
+    pub lockfile: Option<String>,
+    /// Platforms `checklocks` requires a lockfile for.
+    pub platforms: Vec<String>,
+    /// conda solver backend, e.g. `libmamba`.
+    pub solver: Option<String>,
+    /// conda's `channel_priority` for solves: `strict`, `flexible` or `disabled`.
+    pub channel_priority: Option<String>,
+    /// Channels `checklocks` requires every locked package to come from.
+    pub allowed_channels: Vec<String>,
+    /// Policy file `freeze` and `lint` check lockfiles against, relative to the cwd like
+    /// `depfile`.
+    pub policy: Option<String>,
+    pub hash: HashAlgorithm,
+    /// Used instead of `$CONDA_ROOT` when it isn't set.
+    pub conda_root: Option<String>,
+    pub container: ContainerConfig,
+}
+
+impl Config {
+    /// Read the nearest config file from the cwd upwards.  Without one, everything is left at
+    /// its default.
+    pub fn discover() -> Result<Config> {
+        Config::find(&env::current_dir()?)
+    }
+
+    fn find(start: &Path) -> Result<Config> {
+        let mut base_dir = PathBuf::new();
+        for dir in start.ancestors() {
+            let path = dir.join(CONFIG_FILE);
+            if path.is_file() {
+                let table = parse_toml(&path)?;
+                return Config::from_toml(&table, &path, &base_dir);
+            }
+            let path = dir.join(PYPROJECT_FILE);
+            if path.is_file() {
+                let table = parse_toml(&path)?;
+                if let Some(tool_config) = table
+                    .get("tool")
+                    .and_then(|tool| tool.get("conda-lockfile"))
+                {
+                    return Config::from_toml(tool_config, &path, &base_dir);
+                }
+            }
+            // Paths in the config are relative to its directory, so build up a relative path
+            // back to it from where we started.
+            base_dir.push("..");
+        }
+        Ok(Config::default())
+    }
+
+    // `base_dir` is the config file's directory, relative to the cwd.
+    fn from_toml(table: &Value, path: &Path, base_dir: &Path) -> Result<Config> {
+        debug!("Reading config from {}", path.display());
+        let invalid = |key: &str, expected: &str| -> Box<dyn Error> {
+            let msg = format!("{}: `{}` must be {}", path.display(), key, expected);
+            ioError::other(msg).into()
+        };
+        let table = match table.as_table() {
+            Some(table) => table,
+            None => return Err(invalid("tool.conda-lockfile", "a table")),
+        };
+
+        let mut config = Config {
+            path: Some(path.to_path_buf()),
+            ..Config::default()
+        };
+        for (key, value) in table.iter() {
+            match key.as_str() {
+                "depfile" => {
+                    let depfile = value.as_str().ok_or_else(|| invalid(key, "a string"))?;
+                    config.depfile = Some(base_dir.join(depfile).to_str().unwrap().to_string());
+                }
+                "lockfile" => {
+                    let template = value.as_str().ok_or_else(|| invalid(key, "a string"))?;
+                    if let Err(msg) = validate_template(template.to_string()) {
+                        let msg = format!("{}: `{}` {}", path.display(), key, msg);
+                        return Err(ioError::other(msg).into());
+                    }
+                    config.lockfile = Some(template.to_string());
+                }
+                "platforms" => {
+                    let expected = "a list of Darwin and Linux";
+                    for platform in value.as_array().ok_or_else(|| invalid(key, expected))? {
+                        match platform.as_str() {
+                            Some(platform) if PLATFORMS.contains(&platform) => {
+                                config.platforms.push(platform.to_string())
+                            }
+                            _ => return Err(invalid(key, expected)),
+                        }
+                    }
+                }
+                "solver" => config.solver = Some(string_setting(value, key, &invalid)?),
+                "channel-priority" => {
+                    let expected = "\"strict\", \"flexible\" or \"disabled\"";
+                    match value.as_str() {
+                        Some(priority) if CHANNEL_PRIORITIES.contains(&priority) => {
+                            config.channel_priority = Some(priority.to_string())
+                        }
+                        _ => return Err(invalid(key, expected)),
+                    }
+                }
+                "allowed-channels" => {
+                    let channels = value.as_array().ok_or_else(|| invalid(key, "a list"))?;
+                    for channel in channels {
+                        let channel = string_setting(channel, key, &invalid)?;
+                        config.allowed_channels.push(channel);
+                    }
+                }
+                "policy" => {
+                    let policy = string_setting(value, key, &invalid)?;
+                    config.policy = Some(base_dir.join(policy).to_str().unwrap().to_string());
+                }
+                "hash" => {
+                    let expected = "\"sha1\" or \"sha256\"";
+                    config.hash = value
+                        .as_str()
+                        .and_then(HashAlgorithm::from_name)
+                        .ok_or_else(|| invalid(key, expected))?;
+                }
+                "conda-root" => config.conda_root = Some(string_setting(value, key, &invalid)?),
+                "container" => {
+                    let container = value.as_table().ok_or_else(|| invalid(key, "a table"))?;
+                    for (key, value) in container.iter() {
+                        let setting = Some(string_setting(value, key, &invalid)?);
+                        match key.as_str() {
+                            "image" => config.container.image = setting,
+                            "installer" => config.container.installer = setting,
+                            "prefix" => config.container.prefix = setting,
+                            "base-image" => config.container.base_image = setting,
+                            "micromamba-version" => config.container.micromamba_version = setting,
+                            _ => return Err(unknown_key(path, &format!("container.{}", key))),
+                        }
+                    }
+                }
+                _ => return Err(unknown_key(path, key)),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Export the settings conda reads from the environment, unless they are already set there.
+    pub fn apply_to_env(&self) {
+        let settings = [
+            ("CONDA_ROOT", &self.conda_root),
+            ("CONDA_SOLVER", &self.solver),
+            ("CONDA_CHANNEL_PRIORITY", &self.channel_priority),
+        ];
+        for &(var, value) in settings.iter() {
+            if let (Some(value), None) = (value.as_ref(), env::var_os(var)) {
+                debug!("Setting {}={} from the config", var, value);
+                env::set_var(var, value);
+            }
+        }
+    }
+
+    pub fn depfile<'a>(&'a self, matches: &'a ArgMatches) -> &'a str {
+        cli_or_config(matches, "depfile", self.depfile.as_deref()).unwrap()
+    }
+
+    // A depfile given with --depfile must exist.  The configured one is only a default, like
+    // `deps.yml`, and may be missing where only the lockfile was copied.
+    pub fn depfile_is_explicit(&self, matches: &ArgMatches) -> bool {
+        matches.occurrences_of("depfile") > 0
+    }
+
+    pub fn lockfile_template<'a>(&'a self, matches: &'a ArgMatches) -> &'a str {
+        cli_or_config(matches, "lockfile-template", self.lockfile.as_deref())
+            .unwrap_or(LOCKFILE_TEMPLATE)
+    }
+
+    // `--allowed-channels`, then `--check-channels` for each lockfile's own channels, then the
+    // configured channels.
+    pub fn allowed_channels(&self, matches: &ArgMatches) -> AllowedChannels {
+        if let Some(allowed) = matches.values_of("allowed-channels") {
+            return AllowedChannels::Only(channels::allowed(allowed));
+        }
+        if matches.is_present("check-channels") {
+            return AllowedChannels::Listed;
+        }
+        if self.allowed_channels.is_empty() {
+            return AllowedChannels::Any;
+        }
+        let allowed = self.allowed_channels.iter().map(|channel| channel.as_str());
+        AllowedChannels::Only(channels::allowed(allowed))
+    }
+
+    /// The policy from `--policy` or the config, if there is one.
+    pub fn policy(&self, matches: &ArgMatches) -> Result<Option<Policy>> {
+        match cli_or_config(matches, "policy", self.policy.as_deref()) {
+            Some(path) => Ok(Some(Policy::load(Path::new(path))?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn platforms<'a>(&'a self, matches: &'a ArgMatches) -> Vec<&'a str> {
+        match matches.values_of("platforms") {
+            Some(platforms) => platforms.collect(),
+            None => self
+                .platforms
+                .iter()
+                .map(|platform| platform.as_str())
+                .collect(),
+        }
+    }
+}
+
+/// Check a lockfile naming template, in the form clap validators take.  Every platform needs its
+/// own lockfile, so `{platform}` is required.
+pub fn validate_template(template: String) -> ::std::result::Result<(), String> {
+    if !template.contains("{platform}") {
+        return Err("must contain `{platform}`".to_string());
+    }
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map_or(rest.len(), |end| start + end + 1);
+        let placeholder = &rest[start..end];
+        if !TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "has unknown placeholder `{}`; use {}",
+                placeholder,
+                TEMPLATE_PLACEHOLDERS.join(", ")
+            ));
+        }
+        rest = &rest[end..];
+    }
+    Ok(())
+}
+
+/// The value of `arg` if it was given on the command line, then the configured value, then
+/// clap's default.
+pub fn cli_or_config<'a>(
+    matches: &'a ArgMatches,
+    arg: &str,
+    configured: Option<&'a str>,
+) -> Option<&'a str> {
+    if matches.occurrences_of(arg) == 0 && configured.is_some() {
+        return configured;
+    }
+    matches.value_of(arg)
+}
+
+fn string_setting<F>(value: &Value, key: &str, invalid: &F) -> Result<String>
+where
+    F: Fn(&str, &str) -> Box<dyn Error>,
+{
+    match value.as_str() {
+        Some(value) => Ok(value.to_string()),
+        None => Err(invalid(key, "a string")),
+    }
+}
+
+// Typos would otherwise be silently ignored.
+fn unknown_key(path: &Path, key: &str) -> Box<dyn Error> {
+    ioError::other(format!("{}: unknown setting `{}`", path.display(), key)).into()
+}
+
+fn parse_toml(path: &Path) -> Result<Value> {
+    match read_to_string(path)?.parse::<Value>() {
+        Ok(value) => Ok(value),
+        Err(err) => Err(ioError::other(format!("{}: {}", path.display(), err)).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    fn parse(data: &str, base_dir: &str) -> Result<Config> {
+        let value = data.parse::<Value>().unwrap();
+        Config::from_toml(
+            &value,
+            Path::new("conda-lockfile.toml"),
+            Path::new(base_dir),
+        )
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = parse(
+            "depfile = \"env/deps.yml\"
+lockfile = \"{stem}.{platform}.lock.yml\"
+platforms = [\"Linux\", \"Darwin\"]
+solver = \"libmamba\"
+hash = \"sha256\"
+conda-root = \"/opt/conda\"
+channel-priority = \"strict\"
+allowed-channels = [\"conda-forge\", \"defaults\"]
+policy = \"policy.toml\"
+
+[container]
+image = \"debian:bookworm\"
+base-image = \"ubuntu:22.04\"
+",
+            "../..",
+        )
+        .unwrap();
+        assert_eq!(config.depfile.as_deref(), Some("../../env/deps.yml"));
+        assert_eq!(
+            config.lockfile.as_deref(),
+            Some("{stem}.{platform}.lock.yml")
+        );
+        assert_eq!(config.platforms, ["Linux", "Darwin"]);
+        assert_eq!(config.solver.as_deref(), Some("libmamba"));
+        assert_eq!(config.hash, HashAlgorithm::Sha256);
+        assert_eq!(config.conda_root.as_deref(), Some("/opt/conda"));
+        assert_eq!(config.channel_priority.as_deref(), Some("strict"));
+        assert_eq!(config.allowed_channels, ["conda-forge", "defaults"]);
+        assert_eq!(config.policy.as_deref(), Some("../../policy.toml"));
+        assert_eq!(config.container.image.as_deref(), Some("debian:bookworm"));
+        assert_eq!(config.container.base_image.as_deref(), Some("ubuntu:22.04"));
+        assert_eq!(config.container.micromamba_version, None);
+
+        assert_eq!(parse("", "").unwrap().hash, HashAlgorithm::Sha1);
+        assert!(parse("hash = \"md5\"", "").is_err());
+        assert!(parse("platforms = [\"Windows\"]", "").is_err());
+        assert!(parse("channel-priority = \"strong\"", "").is_err());
+        assert!(parse("allowed-channels = \"conda-forge\"", "").is_err());
+        assert!(parse("lockfile = \"deps.lock.yml\"", "").is_err());
+        assert!(parse("lockfile = \"locks/{platform}/{stem}.lock\"", "").is_ok());
+        assert!(parse("depfiel = \"deps.yml\"", "").is_err());
+        assert!(parse("[container]\nimg = \"debian\"", "").is_err());
+    }
+
+    #[test]
+    fn discover_config() {
+        let dir = tempdir().unwrap();
+        let project = dir.path().join("project");
+        let service = project.join("services").join("api");
+        create_dir_all(&service).unwrap();
+        assert_eq!(Config::find(&service).unwrap(), Config::default());
+
+        // A pyproject.toml without our table is skipped.
+        write(
+            service.join("pyproject.toml"),
+            "[tool.black]\nline-length = 100\n",
+        )
+        .unwrap();
+        write(
+            project.join("pyproject.toml"),
+            "[tool.conda-lockfile]\ndepfile = \"deps.yml\"\n",
+        )
+        .unwrap();
+        let config = Config::find(&service).unwrap();
+        assert_eq!(config.path, Some(project.join("pyproject.toml")));
+        assert_eq!(config.depfile.as_deref(), Some("../../deps.yml"));
+
+        // conda-lockfile.toml wins over pyproject.toml in the same directory.
+        write(project.join(CONFIG_FILE), "depfile = \"env.yml\"\n").unwrap();
+        assert_eq!(
+            Config::find(&project).unwrap().depfile.as_deref(),
+            Some("env.yml")
+        );
+    }
+
+    #[test]
+    fn lockfile_templates() {
+        assert!(validate_template(LOCKFILE_TEMPLATE.to_string()).is_ok());
+        assert!(validate_template("{name}.{platform}.lock".to_string()).is_ok());
+        assert!(validate_template("locks/{stem}.lock".to_string()).is_err());
+        assert!(validate_template("{env}.{platform}.lock".to_string()).is_err());
+        assert!(validate_template("{platform}/{stem".to_string()).is_err());
+    }
+
+    #[test]
+    fn hash_algorithms() {
+        let sha1 = HashAlgorithm::Sha1.digest(b"name: app\n");
+        assert_eq!(sha1.len(), 40);
+        assert_eq!(HashAlgorithm::of(&sha1), HashAlgorithm::Sha1);
+        let sha256 = HashAlgorithm::Sha256.digest(b"name: app\n");
+        assert!(sha256.starts_with("sha256:"));
+        assert_eq!(sha256.len(), 7 + 64);
+        assert_eq!(HashAlgorithm::of(&sha256), HashAlgorithm::Sha256);
+    }
+}
@@ -0,0 +1,212 @@
+use std::io::Error as ioError;
+
+use yaml_rust::{yaml, Yaml};
+
+use Result;
+
+/// Named sets of extra dependencies in a depfile, e.g. `dev` and `test`, each frozen into its own
+/// lockfile on top of the base dependencies.
+pub const GROUPS_KEY: &str = "groups";
+/// Records which group a lockfile was frozen for.
+pub const GROUP_KEY: &str = "group";
+
+/// The group names in a depfile, in the order they're declared.
+pub fn group_names(doc: &Yaml) -> Vec<String> {
+    match doc[GROUPS_KEY].as_hash() {
+        Some(groups) => groups
+            .keys()
+            .filter_map(|name| name.as_str())
+            .map(|name| name.to_string())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// The env for one group: the base dependencies plus the group's, named `{name}-{group}`.
+pub fn group_env(doc: &Yaml, group: &str) -> Result<Yaml> {
+    let mut env = merge(doc, &[group])?;
+    if let Yaml::Hash(ref mut env) = env {
+        let name = format!("{}-{}", doc["name"].as_str().unwrap_or("env"), group);
+        set(env, "name", Yaml::String(name));
+    }
+    Ok(env)
+}
+
+/// The base dependencies alone.
+pub fn base_env(doc: &Yaml) -> Result<Yaml> {
+    merge(doc, &[])
+}
+
+/// The base dependencies plus every group's, solved together so that packages shared between
+/// groups resolve to the same versions.
+pub fn combined_env(doc: &Yaml) -> Result<Yaml> {
+    let groups = group_names(doc);
+    let groups: Vec<&str> = groups.iter().map(|group| group.as_str()).collect();
+    merge(doc, &groups)
+}
+
+fn merge(doc: &Yaml, groups: &[&str]) -> Result<Yaml> {
+    let mut env = match *doc {
+        Yaml::Hash(ref env) => env.clone(),
+        _ => return Err(ioError::other("The depfile must be a mapping").into()),
+    };
+    env.remove(&Yaml::from_str(GROUPS_KEY));
+
+    let mut channels = doc["channels"].as_vec().cloned().unwrap_or_default();
+    let mut conda_deps = Vec::new();
+    let mut pip_deps = Vec::new();
+    let mut add_deps = |deps: &Yaml| {
+        for dep in deps.as_vec().map_or(&[][..], |deps| &deps[..]) {
+            match dep["pip"].as_vec() {
+                Some(pip) => pip_deps.extend(pip.iter().cloned()),
+                None => conda_deps.push(dep.clone()),
+            }
+        }
+    };
+    add_deps(&doc["dependencies"]);
+    for group in groups {
+        let spec = &doc[GROUPS_KEY][*group];
+        // A group is either a list of dependencies or a mapping with `dependencies` and
+        // optionally extra `channels`.
+        match *spec {
+            Yaml::Array(_) => add_deps(spec),
+            Yaml::Hash(_) => {
+                add_deps(&spec["dependencies"]);
+                for channel in spec["channels"].as_vec().map_or(&[][..], |c| &c[..]) {
+                    if !channels.contains(channel) {
+                        channels.push(channel.clone());
+                    }
+                }
+            }
+            _ => {
+                let msg = format!("Group {} has no dependencies", group);
+                return Err(ioError::other(msg).into());
+            }
+        }
+    }
+
+    if !pip_deps.is_empty() {
+        let mut pip = yaml::Hash::new();
+        pip.insert(Yaml::from_str("pip"), Yaml::Array(pip_deps));
+        conda_deps.push(Yaml::Hash(pip));
+    }
+    set(&mut env, "dependencies", Yaml::Array(conda_deps));
+    if !channels.is_empty() {
+        set(&mut env, "channels", Yaml::Array(channels));
+    }
+    Ok(Yaml::Hash(env))
+}
+
+// Replace a key's value where it is, so the env keeps the depfile's layout.
+fn set(env: &mut yaml::Hash, key: &str, value: Yaml) {
+    match env.get_mut(&Yaml::from_str(key)) {
+        Some(existing) => *existing = value,
+        None => {
+            env.insert(Yaml::from_str(key), value);
+        }
+    }
+}
+
+/// Pins for every package in an exported env: conda match specs for `CONDA_PINNED_PACKAGES` and
+/// pip constraints for `PIP_CONSTRAINT`.
+pub fn pins(export: &Yaml) -> (Vec<String>, Vec<String>) {
+    let mut conda_pins = Vec::new();
+    let mut pip_pins = Vec::new();
+    for dep in export["dependencies"]
+        .as_vec()
+        .map_or(&[][..], |deps| &deps[..])
+    {
+        match (dep.as_str(), dep["pip"].as_vec()) {
+            (Some(spec), _) => conda_pins.push(spec.to_string()),
+            (None, Some(pip)) => pip_pins.extend(
+                pip.iter()
+                    .filter_map(|req| req.as_str())
+                    .map(|req| req.to_string()),
+            ),
+            _ => {}
+        }
+    }
+    (conda_pins, pip_pins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use yaml_rust::YamlLoader;
+
+    const DEPFILE: &str = "name: app
+channels:
+  - conda-forge
+dependencies:
+  - python=3.11
+  - numpy
+  - pip:
+    - requests
+groups:
+  dev:
+    channels:
+      - bioconda
+      - conda-forge
+    dependencies:
+      - pytest
+      - pip:
+        - black
+  test:
+    - pytest-cov
+";
+
+    #[test]
+    fn group_envs() {
+        let doc = &YamlLoader::load_from_str(DEPFILE).unwrap()[0];
+        assert_eq!(group_names(doc), ["dev", "test"]);
+
+        let dev = group_env(doc, "dev").unwrap();
+        let expected = &YamlLoader::load_from_str(
+            "name: app-dev
+channels:
+  - conda-forge
+  - bioconda
+dependencies:
+  - python=3.11
+  - numpy
+  - pytest
+  - pip:
+    - requests
+    - black
+",
+        )
+        .unwrap()[0];
+        assert_eq!(&dev, expected);
+
+        let test = group_env(doc, "test").unwrap();
+        assert_eq!(test["name"].as_str(), Some("app-test"));
+        assert_eq!(test["dependencies"][2].as_str(), Some("pytest-cov"));
+        assert!(test[GROUPS_KEY].is_badvalue());
+
+        let combined = combined_env(doc).unwrap();
+        assert_eq!(combined["name"].as_str(), Some("app"));
+        assert_eq!(combined["dependencies"].as_vec().unwrap().len(), 5);
+        assert!(group_env(doc, "docs").is_err());
+    }
+
+    #[test]
+    fn pins_from_export() {
+        let export = &YamlLoader::load_from_str(
+            "name: app
+dependencies:
+  - python=3.11.5=hab00c5b_0
+  - numpy=1.26.0=py311h64a7726_0
+  - pip:
+    - requests==2.31.0
+",
+        )
+        .unwrap()[0];
+        let (conda_pins, pip_pins) = pins(export);
+        assert_eq!(
+            conda_pins,
+            ["python=3.11.5=hab00c5b_0", "numpy=1.26.0=py311h64a7726_0"]
+        );
+        assert_eq!(pip_pins, ["requests==2.31.0"]);
+    }
+}
@@ -0,0 +1,424 @@
+use std::fs::{canonicalize, read_to_string};
+use std::io::Error as ioError;
+use std::path::{Path, PathBuf};
+
+use yaml_rust::{yaml, Yaml, YamlLoader};
+
+use groups::GROUPS_KEY;
+use requirements::PipEntry;
+use spec::{MatchSpec, PipRequirement};
+use Result;
+
+/// Other depfiles merged underneath this one, relative to it, e.g. `include: [../common/deps.yml]`.
+pub const INCLUDE_KEY: &str = "include";
+
+/// A depfile with everything it includes merged in.
+#[derive(Debug)]
+pub struct Depfile {
+    pub doc: Yaml,
+    /// The included depfiles that were read, in order.
+    pub includes: Vec<PathBuf>,
+}
+
+/// Read a depfile and merge in its includes, recursively.  Included files come first, so the
+/// depfile's own channels take priority over theirs and its own specs replace theirs for the same
+/// package.
+pub fn read_depfile(path: &Path) -> Result<Depfile> {
+    let mut depfile = Depfile {
+        doc: Yaml::Null,
+        includes: Vec::new(),
+    };
+    depfile.doc = read_included(path, &mut Vec::new(), &mut depfile.includes)?;
+    Ok(depfile)
+}
+
+fn read_included(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    includes: &mut Vec<PathBuf>,
+) -> Result<Yaml> {
+    let doc = load(path)?;
+    let include_paths: Vec<&str> = match doc[INCLUDE_KEY] {
+        Yaml::BadValue => return Ok(doc),
+        Yaml::String(ref include) => vec![include],
+        Yaml::Array(ref include) => include.iter().filter_map(|path| path.as_str()).collect(),
+        _ => {
+            let msg = format!(
+                "{}: include must be a path or a list of paths",
+                path.display()
+            );
+            return Err(ioError::other(msg).into());
+        }
+    };
+
+    let canonical = canonicalize(path)?;
+    stack.push(canonical);
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = Yaml::Hash(yaml::Hash::new());
+    for include_path in include_paths {
+        let included_path = base_dir.join(include_path);
+        let canonical = match canonicalize(&included_path) {
+            Ok(canonical) => canonical,
+            Err(err) => {
+                let msg = format!("Unable to include {}: {}", included_path.display(), err);
+                return Err(ioError::other(msg).into());
+            }
+        };
+        if stack.contains(&canonical) {
+            let cycle: Vec<String> = stack
+                .iter()
+                .chain(Some(&canonical))
+                .map(|path| path.display().to_string())
+                .collect();
+            let msg = format!("Depfile include cycle: {}", cycle.join(" -> "));
+            return Err(ioError::other(msg).into());
+        }
+        // A file included by more than one path only needs merging once.
+        if includes.contains(&canonical) {
+            continue;
+        }
+        includes.push(canonical);
+        let mut included = read_included(&included_path, stack, includes)?;
+        // Files the included depfile references are relative to it, not to us.
+        let included_dir = Path::new(include_path)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        rebase_pip_paths(&mut included, included_dir);
+        merged = merge(merged, included);
+    }
+    stack.pop();
+    Ok(merge(merged, doc))
+}
+
+fn load(path: &Path) -> Result<Yaml> {
+    let data = match read_to_string(path) {
+        Ok(data) => data,
+        Err(err) => {
+            let msg = format!("Unable to read {}: {}", path.display(), err);
+            return Err(ioError::other(msg).into());
+        }
+    };
+    let docs = YamlLoader::load_from_str(&data)?;
+    match docs.into_iter().next() {
+        Some(doc @ Yaml::Hash(_)) => Ok(doc),
+        None => Ok(Yaml::Hash(yaml::Hash::new())),
+        _ => {
+            let msg = format!("{} must be a mapping", path.display());
+            Err(ioError::other(msg).into())
+        }
+    }
+}
+
+// Merge `over` on top of `base`: scalar keys are replaced, channels are combined with `over`'s
+// first, dependencies are combined with `over`'s spec winning for a package in both, and groups
+// are merged by name.
+fn merge(base: Yaml, over: Yaml) -> Yaml {
+    let (mut merged, over) = match (base, over) {
+        (Yaml::Hash(base), Yaml::Hash(over)) => (base, over),
+        (_, over) => return over,
+    };
+    for (key, value) in over {
+        if key.as_str() == Some(INCLUDE_KEY) {
+            continue;
+        }
+        // Keys keep their place, so the merged depfile reads like the files it came from.
+        match merged.get_mut(&key) {
+            Some(existing) => *existing = merge_value(key.as_str(), existing, value),
+            None => {
+                merged.insert(key, value);
+            }
+        }
+    }
+    Yaml::Hash(merged)
+}
+
+fn merge_value(key: Option<&str>, base: &Yaml, over: Yaml) -> Yaml {
+    match (key, base) {
+        // Channel order is priority, so `over`'s own order comes first.
+        (Some("channels"), Yaml::Array(base_channels)) => {
+            let mut channels = over.into_vec().unwrap_or_default();
+            for channel in base_channels {
+                if !channels.contains(channel) {
+                    channels.push(channel.clone());
+                }
+            }
+            Yaml::Array(channels)
+        }
+        (Some("dependencies"), base) => merge_deps(base, &over),
+        (Some(GROUPS_KEY), Yaml::Hash(groups)) => {
+            let mut groups = groups.clone();
+            for (name, group) in over.into_hash().unwrap_or_default() {
+                match groups.get_mut(&name) {
+                    Some(existing) => *existing = group,
+                    None => {
+                        groups.insert(name, group);
+                    }
+                }
+            }
+            Yaml::Hash(groups)
+        }
+        _ => over,
+    }
+}
+
+fn merge_deps(base: &Yaml, over: &Yaml) -> Yaml {
+    let (base_conda, base_pip) = split_deps(base);
+    let (over_conda, over_pip) = split_deps(over);
+    let mut conda_deps = replace_by_name(base_conda, over_conda, conda_name);
+    let pip_deps = replace_by_name(base_pip, over_pip, pip_name);
+    if !pip_deps.is_empty() {
+        let mut pip = yaml::Hash::new();
+        pip.insert(Yaml::from_str("pip"), Yaml::Array(pip_deps));
+        conda_deps.push(Yaml::Hash(pip));
+    }
+    Yaml::Array(conda_deps)
+}
+
+fn split_deps(deps: &Yaml) -> (Vec<Yaml>, Vec<Yaml>) {
+    let mut conda_deps = Vec::new();
+    let mut pip_deps = Vec::new();
+    for dep in deps.as_vec().map_or(&[][..], |deps| &deps[..]) {
+        match dep["pip"].as_vec() {
+            Some(pip) => pip_deps.extend(pip.iter().cloned()),
+            None => conda_deps.push(dep.clone()),
+        }
+    }
+    (conda_deps, pip_deps)
+}
+
+// `base` with each entry `over` has a name for replaced in place, followed by the rest of `over`.
+fn replace_by_name(
+    base: Vec<Yaml>,
+    over: Vec<Yaml>,
+    name: fn(&str) -> Option<String>,
+) -> Vec<Yaml> {
+    let mut merged = base;
+    for dep in over {
+        let dep_name = dep.as_str().and_then(name);
+        let position = merged.iter().position(|existing| {
+            dep_name.is_some() && existing.as_str().and_then(name) == dep_name
+        });
+        match position {
+            Some(idx) => merged[idx] = dep,
+            None => merged.push(dep),
+        }
+    }
+    merged
+}
+
+fn conda_name(spec: &str) -> Option<String> {
+    MatchSpec::parse(spec).ok().map(|spec| spec.name)
+}
+
+fn pip_name(line: &str) -> Option<String> {
+    if line.trim().starts_with('-') {
+        return None;
+    }
+    PipRequirement::parse(line)
+        .ok()
+        .map(|req| req.normalized_name())
+}
+
+// Point relative requirements files, constraints files and local paths in the pip section at
+// `dir`.
+fn rebase_pip_paths(doc: &mut Yaml, dir: &Path) {
+    if dir.as_os_str().is_empty() {
+        return;
+    }
+    map_pip_paths(doc, |path| {
+        if Path::new(path).is_absolute() || path.contains("://") {
+            return None;
+        }
+        Some(dir.join(path).to_str()?.to_string())
+    });
+}
+
+/// Replace the requirements files, constraints files and local paths in a depfile's pip sections,
+/// its own and its groups', with `rebase(path)`, leaving those it returns `None` for alone.
+pub fn map_pip_paths<F: Fn(&str) -> Option<String>>(doc: &mut Yaml, rebase: F) {
+    let doc = match *doc {
+        Yaml::Hash(ref mut doc) => doc,
+        _ => return,
+    };
+    if let Some(deps) = doc.get_mut(&Yaml::from_str("dependencies")) {
+        map_deps_pip_paths(deps, &rebase);
+    }
+    let groups = match doc.get_mut(&Yaml::from_str(GROUPS_KEY)) {
+        Some(&mut Yaml::Hash(ref mut groups)) => groups,
+        _ => return,
+    };
+    // A group is either a list of dependencies or a mapping with `dependencies`.
+    for (_, group) in groups.iter_mut() {
+        match *group {
+            Yaml::Array(_) => map_deps_pip_paths(group, &rebase),
+            Yaml::Hash(ref mut group) => {
+                if let Some(deps) = group.get_mut(&Yaml::from_str("dependencies")) {
+                    map_deps_pip_paths(deps, &rebase);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn map_deps_pip_paths<F: Fn(&str) -> Option<String>>(deps: &mut Yaml, rebase: &F) {
+    let deps = match *deps {
+        Yaml::Array(ref mut deps) => deps,
+        _ => return,
+    };
+    for dep in deps.iter_mut() {
+        let pips = match *dep {
+            Yaml::Hash(ref mut dep) => dep.get_mut(&Yaml::from_str("pip")),
+            _ => None,
+        };
+        let pips = match pips {
+            Some(&mut Yaml::Array(ref mut pips)) => pips,
+            _ => continue,
+        };
+        for pip in pips.iter_mut() {
+            let rebased = match pip.as_str().map(PipEntry::parse) {
+                Some(Ok(PipEntry::RequirementsFile(path))) => {
+                    rebase(&path).map(PipEntry::RequirementsFile)
+                }
+                Some(Ok(PipEntry::ConstraintsFile(path))) => {
+                    rebase(&path).map(PipEntry::ConstraintsFile)
+                }
+                Some(Ok(PipEntry::Editable(ref path))) if path.starts_with('.') => {
+                    rebase(path).map(PipEntry::Editable)
+                }
+                Some(Ok(PipEntry::Url(ref path))) if path.starts_with('.') => {
+                    rebase(path).map(PipEntry::Url)
+                }
+                _ => None,
+            };
+            if let Some(rebased) = rebased {
+                *pip = Yaml::String(rebased.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir, File};
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    fn write(path: &Path, data: &str) {
+        File::create(path)
+            .unwrap()
+            .write_all(data.as_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn merge_includes() {
+        let dir = tempdir().unwrap();
+        let common = dir.path().join("common");
+        let service = dir.path().join("service");
+        create_dir(&common).unwrap();
+        create_dir(&service).unwrap();
+        write(
+            &common.join("deps.yml"),
+            "name: common
+channels:
+  - conda-forge
+  - defaults
+dependencies:
+  - python=3.10
+  - numpy
+  - pip:
+    - -r requirements.txt
+    - requests==2.30
+groups:
+  dev:
+    - pytest
+    - pip:
+      - -r dev-requirements.txt
+  docs:
+    dependencies:
+      - pip:
+        - ./docs/theme
+",
+        );
+        write(
+            &service.join("deps.yml"),
+            "name: service
+include:
+  - ../common/deps.yml
+channels:
+  - bioconda
+  - conda-forge
+dependencies:
+  - python=3.11
+  - pandas
+  - pip:
+    - requests==2.31
+",
+        );
+
+        let depfile = read_depfile(&service.join("deps.yml")).unwrap();
+        let expected = &YamlLoader::load_from_str(
+            "name: service
+channels:
+  - bioconda
+  - conda-forge
+  - defaults
+dependencies:
+  - python=3.11
+  - numpy
+  - pandas
+  - pip:
+    - -r ../common/requirements.txt
+    - requests==2.31
+groups:
+  dev:
+    - pytest
+    - pip:
+      - -r ../common/dev-requirements.txt
+  docs:
+    dependencies:
+      - pip:
+        - ../common/./docs/theme
+",
+        )
+        .unwrap()[0];
+        assert_eq!(&depfile.doc, expected);
+        assert_eq!(
+            depfile.includes,
+            [canonicalize(common.join("deps.yml")).unwrap()]
+        );
+
+        // Depfiles without includes are read as they are.
+        let depfile = read_depfile(&common.join("deps.yml")).unwrap();
+        assert_eq!(depfile.doc["name"].as_str(), Some("common"));
+        assert!(depfile.includes.is_empty());
+    }
+
+    #[test]
+    fn include_cycles() {
+        let dir = tempdir().unwrap();
+        write(&dir.path().join("a.yml"), "name: a\ninclude: b.yml\n");
+        write(&dir.path().join("b.yml"), "name: b\ninclude: [a.yml]\n");
+        let err = read_depfile(&dir.path().join("a.yml")).unwrap_err();
+        assert!(err.to_string().contains("include cycle"), "{}", err);
+
+        write(&dir.path().join("c.yml"), "name: c\ninclude: missing.yml\n");
+        assert!(read_depfile(&dir.path().join("c.yml")).is_err());
+
+        // Including the same file twice isn't a cycle.
+        write(
+            &dir.path().join("base.yml"),
+            "name: base\ndependencies:\n  - python\n",
+        );
+        write(
+            &dir.path().join("d.yml"),
+            "name: d\ninclude: [base.yml, ./base.yml]\n",
+        );
+        let depfile = read_depfile(&dir.path().join("d.yml")).unwrap();
+        assert_eq!(depfile.doc["dependencies"].as_vec().unwrap().len(), 1);
+    }
+}
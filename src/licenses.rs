@@ -0,0 +1,198 @@
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+use packages::read_conda_meta;
+use pip::installed_dists;
+use policy::{Package, Policy};
+use Result;
+
+const CSV_COLUMNS: &[&str] = &["name", "version", "installer", "license", "status"];
+
+/// One row of the license report.
+#[derive(Debug, PartialEq)]
+pub struct LicenseEntry {
+    pub name: String,
+    pub version: String,
+    /// `conda` or `pip`.
+    pub installer: &'static str,
+    pub license: Option<String>,
+    /// Why the package needs a look, if it does.
+    pub flag: Option<String>,
+}
+
+impl LicenseEntry {
+    pub fn status(&self) -> &str {
+        self.flag.as_deref().unwrap_or("ok")
+    }
+
+    fn fields(&self) -> [&str; 5] {
+        [
+            &self.name,
+            &self.version,
+            self.installer,
+            self.license.as_deref().unwrap_or(""),
+            self.status(),
+        ]
+    }
+}
+
+/// The license of every package installed at `prefix`, flagging unknown licenses and any the
+/// policy doesn't allow.
+pub fn report(prefix: &Path, policy: Option<&Policy>) -> Result<Vec<LicenseEntry>> {
+    let mut entries = Vec::new();
+    for record in read_conda_meta(prefix)? {
+        entries.push(entry(record.name, record.version, "conda", record.license));
+    }
+    for dist in installed_dists(prefix)? {
+        entries.push(entry(dist.name, dist.version, "pip", dist.license));
+    }
+    if let Some(policy) = policy {
+        for entry in entries.iter_mut().filter(|entry| entry.flag.is_none()) {
+            let package = Package {
+                name: entry.name.clone(),
+                version: entry.version.clone(),
+                license: entry.license.clone(),
+                pip: entry.installer == "pip",
+            };
+            let denials = policy.denials(&package)?;
+            if !denials.is_empty() {
+                entry.flag = Some(denials.join("; "));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn entry(
+    name: String,
+    version: String,
+    installer: &'static str,
+    license: Option<String>,
+) -> LicenseEntry {
+    // conda-build writes an empty license when the recipe has none.
+    let license = license
+        .map(|license| license.trim().to_string())
+        .filter(|license| !license.is_empty() && !license.eq_ignore_ascii_case("unknown"));
+    let flag = match license {
+        Some(_) => None,
+        None => Some("unknown license".to_string()),
+    };
+    LicenseEntry {
+        name,
+        version,
+        installer,
+        license,
+        flag,
+    }
+}
+
+pub fn to_csv(entries: &[LicenseEntry]) -> String {
+    let mut csv = CSV_COLUMNS.join(",");
+    csv.push_str("\r\n");
+    for entry in entries {
+        let fields: Vec<String> = entry
+            .fields()
+            .iter()
+            .map(|field| csv_field(field))
+            .collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// Quote a field as RFC 4180 requires; license expressions often contain commas.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub fn to_json(entries: &[LicenseEntry]) -> Value {
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            let mut row = Map::new();
+            for (column, field) in CSV_COLUMNS.iter().zip(entry.fields().iter()) {
+                row.insert(column.to_string(), Value::String(field.to_string()));
+            }
+            row.insert(
+                "license".to_string(),
+                entry
+                    .license
+                    .as_ref()
+                    .map_or(Value::Null, |license| Value::String(license.clone())),
+            );
+            Value::Object(row)
+        })
+        .collect();
+    Value::Array(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use policy::DenyRule;
+
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn license_report() {
+        let prefix = tempdir().unwrap();
+        let conda_meta = prefix.path().join("conda-meta");
+        create_dir_all(&conda_meta).unwrap();
+        let records = [
+            ("readline", "8.2", "GPL-3.0-only"),
+            ("zlib", "1.2.13", "Zlib"),
+            ("tzdata", "2023c", ""),
+        ];
+        for &(name, version, license) in records.iter() {
+            let meta = format!(
+                r#"{{"name": "{}", "version": "{}", "build": "0", "license": "{}"}}"#,
+                name, version, license
+            );
+            write(
+                conda_meta.join(format!("{}-{}-0.json", name, version)),
+                meta,
+            )
+            .unwrap();
+        }
+        let dist_info = prefix
+            .path()
+            .join("lib/python3.11/site-packages/attrs-23.1.0.dist-info");
+        create_dir_all(&dist_info).unwrap();
+        write(
+            dist_info.join("METADATA"),
+            "Name: attrs\nVersion: 23.1.0\nLicense-Expression: MIT\n",
+        )
+        .unwrap();
+
+        let policy = Policy {
+            deny: vec![DenyRule {
+                license: Some("GPL*".to_string()),
+                ..DenyRule::default()
+            }],
+            ..Policy::default()
+        };
+        let entries = report(prefix.path(), Some(&policy)).unwrap();
+        assert_eq!(
+            to_csv(&entries),
+            "name,version,installer,license,status\r
+readline,8.2,conda,GPL-3.0-only,denied (license GPL*)\r
+tzdata,2023c,conda,,unknown license\r
+zlib,1.2.13,conda,Zlib,ok\r
+attrs,23.1.0,pip,MIT,ok\r
+"
+        );
+        let json = to_json(&entries);
+        assert_eq!(json[1]["license"], Value::Null);
+        assert_eq!(json[3]["installer"], "pip");
+        assert_eq!(json[3]["status"], "ok");
+    }
+}
@@ -0,0 +1,492 @@
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, File};
+use std::io::prelude::*;
+use std::io::Error as ioError;
+use std::path::{Path, PathBuf};
+
+use glob::glob;
+use md5;
+use serde_json::{self, Value};
+use sha2::{Digest, Sha256};
+use yaml_rust::{yaml, Yaml};
+
+use spec::MatchSpec;
+use {run_command, Result};
+
+/// The lockfile section holding per-package metadata that `conda env export` leaves out.
+pub const PACKAGES_KEY: &str = "packages";
+
+/// A conda package as recorded in `conda-meta/*.json` or in a lockfile's `packages` section.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackageRecord {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub channel: Option<String>,
+    pub subdir: Option<String>,
+    pub url: Option<String>,
+    pub md5: Option<String>,
+    pub sha256: Option<String>,
+    pub license: Option<String>,
+    // Only known for installed packages.
+    tarball: Option<PathBuf>,
+}
+
+impl PackageRecord {
+    fn from_conda_meta(meta: &Value) -> Option<PackageRecord> {
+        let field = |key: &str| meta[key].as_str().map(|value| value.to_string());
+        let subdir = field("subdir");
+        let tarball = match field("package_tarball_full_path") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => match (field("extracted_package_dir"), field("fn")) {
+                (Some(dir), Some(filename)) => {
+                    Path::new(&dir).parent().map(|pkgs| pkgs.join(filename))
+                }
+                _ => None,
+            },
+        };
+        Some(PackageRecord {
+            name: field("name")?,
+            version: field("version")?,
+            build: field("build")?,
+            channel: field("channel").map(|channel| channel_name(&channel, subdir.as_ref())),
+            subdir,
+            url: field("url"),
+            md5: field("md5"),
+            sha256: field("sha256"),
+            license: field("license"),
+            tarball,
+        })
+    }
+
+    fn from_yaml(name: &str, data: &Yaml) -> PackageRecord {
+        let field = |key: &str| data[key].as_str().map(|value| value.to_string());
+        PackageRecord {
+            name: name.to_string(),
+            version: field("version").unwrap_or_default(),
+            build: field("build").unwrap_or_default(),
+            channel: field("channel"),
+            subdir: field("subdir"),
+            url: field("url"),
+            md5: field("md5"),
+            sha256: field("sha256"),
+            license: field("license"),
+            tarball: None,
+        }
+    }
+
+    // Only packages pinned to an exact version and build can be located.
+    fn from_spec(spec: &MatchSpec) -> Option<PackageRecord> {
+        Some(PackageRecord {
+            name: spec.name.clone(),
+            version: spec.exact_version()?.to_string(),
+            build: spec.build.clone()?,
+            channel: spec.channel.clone(),
+            subdir: spec.subdir.clone(),
+            ..PackageRecord::default()
+        })
+    }
+
+    /// The package's archive filenames.  Without a URL either archive format may be in use.
+    pub fn filenames(&self) -> Vec<String> {
+        match self.url.as_ref().and_then(|url| url.rsplit('/').next()) {
+            Some(filename) if !filename.is_empty() => vec![filename.to_string()],
+            _ => [".conda", ".tar.bz2"]
+                .iter()
+                .map(|ext| format!("{}{}", self.dist_name(), ext))
+                .collect(),
+        }
+    }
+
+    // `zlib-1.2.13-hd590300_5`, the name of the package's directory in the package cache.
+    fn dist_name(&self) -> String {
+        format!("{}-{}-{}", self.name, self.version, self.build)
+    }
+
+    fn to_yaml(&self) -> Yaml {
+        let mut data = yaml::Hash::new();
+        data.insert(
+            Yaml::from_str("version"),
+            Yaml::String(self.version.clone()),
+        );
+        data.insert(Yaml::from_str("build"), Yaml::String(self.build.clone()));
+        let optional = [
+            ("channel", &self.channel),
+            ("subdir", &self.subdir),
+            ("url", &self.url),
+            ("md5", &self.md5),
+            ("sha256", &self.sha256),
+            ("license", &self.license),
+        ];
+        for &(key, value) in optional.iter() {
+            if let Some(ref value) = *value {
+                data.insert(Yaml::from_str(key), Yaml::String(value.clone()));
+            }
+        }
+        Yaml::Hash(data)
+    }
+}
+
+/// `https://conda.anaconda.org/conda-forge/linux-64` -> `conda-forge`
+pub fn channel_name(channel: &str, subdir: Option<&String>) -> String {
+    let mut channel = channel.trim_end_matches('/');
+    if let Some(subdir) = subdir {
+        channel = channel
+            .strip_suffix(subdir.as_str())
+            .map_or(channel, |c| c.trim_end_matches('/'));
+    }
+    for base in &["https://conda.anaconda.org/", "https://repo.anaconda.com/"] {
+        if let Some(name) = channel.strip_prefix(base) {
+            return name.to_string();
+        }
+    }
+    channel.to_string()
+}
+
+/// Read the records of every conda package installed at `prefix`.
+pub fn read_conda_meta(prefix: &Path) -> Result<Vec<PackageRecord>> {
+    let pattern = prefix.join("conda-meta").join("*.json");
+    let mut records = Vec::new();
+    for path in glob(pattern.to_str().unwrap())? {
+        let meta: Value = serde_json::from_str(&read_to_string(path?)?)?;
+        // conda-meta also holds files that aren't package records.
+        if let Some(record) = PackageRecord::from_conda_meta(&meta) {
+            records.push(record);
+        }
+    }
+    records.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(records)
+}
+
+/// The `packages` section to write into a lockfile.
+pub fn packages_yaml(records: &[PackageRecord]) -> Yaml {
+    let mut packages = yaml::Hash::new();
+    for record in records {
+        packages.insert(Yaml::String(record.name.clone()), record.to_yaml());
+    }
+    Yaml::Hash(packages)
+}
+
+/// The records in a lockfile's `packages` section.  Lockfiles frozen by older versions have none.
+pub fn read_locked_packages(doc: &Yaml) -> Vec<PackageRecord> {
+    match doc[PACKAGES_KEY].as_hash() {
+        Some(packages) => packages
+            .iter()
+            .filter_map(|(name, data)| {
+                name.as_str()
+                    .map(|name| PackageRecord::from_yaml(name, data))
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// The pinned conda packages in a lockfile's `dependencies`, for lockfiles without a `packages`
+/// section.
+pub fn exported_packages(doc: &Yaml) -> Result<Vec<PackageRecord>> {
+    let mut records = Vec::new();
+    for dep in doc["dependencies"].as_vec().unwrap_or(&Vec::new()) {
+        if let Some(dep) = dep.as_str() {
+            let spec = MatchSpec::parse(dep)?;
+            match PackageRecord::from_spec(&spec) {
+                Some(record) => records.push(record),
+                None => warn!("{} is not pinned to a build", dep),
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// The packages that are in none of the package caches and not in the local channel.
+pub fn missing_locally<'a>(
+    locked: &'a [PackageRecord],
+    pkgs_dirs: &[PathBuf],
+    channel: Option<&Path>,
+) -> Vec<&'a PackageRecord> {
+    // Without a known subdir, look through all of the channel's.
+    let channel_subdirs: Vec<PathBuf> = match channel.map(read_dir) {
+        Some(Ok(entries)) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect(),
+        _ => Vec::new(),
+    };
+    locked
+        .iter()
+        .filter(|record| {
+            let filenames = record.filenames();
+            let cached = pkgs_dirs.iter().any(|pkgs| {
+                pkgs.join(record.dist_name()).is_dir()
+                    || filenames
+                        .iter()
+                        .any(|filename| pkgs.join(filename).is_file())
+            });
+            let subdirs = match (channel, record.subdir.as_ref()) {
+                (Some(channel), Some(subdir)) => vec![channel.join(subdir)],
+                _ => channel_subdirs.clone(),
+            };
+            let in_channel = subdirs.iter().any(|subdir| {
+                filenames
+                    .iter()
+                    .any(|filename| subdir.join(filename).is_file())
+            });
+            !cached && !in_channel
+        })
+        .collect()
+}
+
+/// Download every package into `dir`, laid out as a channel (`dir/<subdir>/<filename>`).
+/// Packages already there with the right checksums are not downloaded again.
+pub fn fetch(locked: &[PackageRecord], dir: &Path) -> Result<()> {
+    for record in locked {
+        let url = match record.url {
+            Some(ref url) => url,
+            None => {
+                let msg = format!("The lockfile has no URL for {}", record.name);
+                return Err(ioError::other(msg).into());
+            }
+        };
+        let subdir = record
+            .subdir
+            .as_ref()
+            .map_or("noarch", |subdir| subdir.as_str());
+        create_dir_all(dir.join(subdir))?;
+        let dest = dir.join(subdir).join(&record.filenames()[0]);
+        if dest.is_file() && checksums_match(record, &dest)? {
+            debug!("{} is already fetched", dest.display());
+            continue;
+        }
+        info!("Fetching {}", url);
+        run_command(
+            "curl",
+            &[
+                "--fail",
+                "--silent",
+                "--show-error",
+                "--location",
+                "--retry",
+                "3",
+                "--output",
+                dest.to_str().unwrap(),
+                url,
+            ],
+        )?;
+        if !checksums_match(record, &dest)? {
+            remove_file(&dest)?;
+            let msg = format!("{} does not match the lockfile's checksums", url);
+            return Err(ioError::other(msg).into());
+        }
+    }
+    // conda expects every channel to have a noarch subdir.
+    create_dir_all(dir.join("noarch"))?;
+    Ok(())
+}
+
+fn checksums_match(record: &PackageRecord, path: &Path) -> Result<bool> {
+    let (md5, sha256) = file_checksums(path)?;
+    Ok(record.md5.iter().all(|expected| *expected == md5)
+        && record.sha256.iter().all(|expected| *expected == sha256))
+}
+
+/// Compare the packages installed at `prefix` with the lockfile's records.  Returns a
+/// description of every mismatch.
+///
+/// Where the downloaded tarball is still in the package cache its checksums are computed
+/// directly; otherwise the checksums conda recorded at install time are used.
+pub fn verify_checksums(prefix: &Path, locked: &[PackageRecord]) -> Result<Vec<String>> {
+    let installed = read_conda_meta(prefix)?;
+    let mut problems = Vec::new();
+    for record in installed.iter() {
+        let expected = match locked.iter().find(|expected| expected.name == record.name) {
+            Some(expected) => expected,
+            None => {
+                problems.push(format!(
+                    "{} is installed but not in the lockfile",
+                    record.name
+                ));
+                continue;
+            }
+        };
+        if (&expected.version, &expected.build) != (&record.version, &record.build) {
+            problems.push(format!(
+                "{} is {}={} but the lockfile has {}={}",
+                record.name, record.version, record.build, expected.version, expected.build
+            ));
+            continue;
+        }
+
+        let (md5, sha256) = match record.tarball {
+            Some(ref tarball) if tarball.is_file() => {
+                debug!("Hashing {}", tarball.display());
+                let (md5, sha256) = file_checksums(tarball)?;
+                (Some(md5), Some(sha256))
+            }
+            _ => (record.md5.clone(), record.sha256.clone()),
+        };
+        let checks = [
+            ("md5", &expected.md5, &md5),
+            ("sha256", &expected.sha256, &sha256),
+        ];
+        for &(kind, expected, found) in checks.iter() {
+            if let (Some(expected), Some(found)) = (expected, found) {
+                if expected != found {
+                    problems.push(format!(
+                        "{} {} is {} but the lockfile has {}",
+                        record.name, kind, found, expected
+                    ));
+                }
+            }
+        }
+    }
+    for expected in locked {
+        if !installed.iter().any(|record| record.name == expected.name) {
+            problems.push(format!(
+                "{} is in the lockfile but not installed",
+                expected.name
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// The md5 and sha256 of a file, as lowercase hex.
+pub fn file_checksums(path: &Path) -> Result<(String, String)> {
+    let mut file = File::open(path)?;
+    let mut md5 = md5::Context::new();
+    let mut sha256 = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        md5.consume(&buffer[..count]);
+        sha256.update(&buffer[..count]);
+    }
+    let sha256: String = sha256
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok((format!("{:x}", md5.compute()), sha256))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::create_dir_all;
+
+    use tempfile::tempdir;
+    use yaml_rust::{YamlEmitter, YamlLoader};
+
+    fn write_meta(prefix: &Path, pkgs: &Path, name: &str, md5: &str) {
+        let filename = format!("{}-1.0-0.tar.bz2", name);
+        let meta = format!(
+            r#"{{"name": "{name}", "version": "1.0", "build": "0", "subdir": "linux-64",
+                "channel": "https://conda.anaconda.org/conda-forge/linux-64",
+                "url": "https://conda.anaconda.org/conda-forge/linux-64/{filename}",
+                "md5": "{md5}", "fn": "{filename}",
+                "extracted_package_dir": "{pkgs}/{name}-1.0-0"}}"#,
+            name = name,
+            md5 = md5,
+            filename = filename,
+            pkgs = pkgs.display(),
+        );
+        File::create(
+            prefix
+                .join("conda-meta")
+                .join(format!("{}-1.0-0.json", name)),
+        )
+        .unwrap()
+        .write_all(meta.as_bytes())
+        .unwrap();
+    }
+
+    #[test]
+    fn conda_meta_round_trip() {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("conda-meta")).unwrap();
+        write_meta(dir.path(), dir.path(), "zlib", "abc");
+        File::create(dir.path().join("conda-meta").join("history")).unwrap();
+
+        let records = read_conda_meta(dir.path()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].channel.as_ref().unwrap(), "conda-forge");
+        assert_eq!(
+            records[0].tarball,
+            Some(dir.path().join("zlib-1.0-0.tar.bz2"))
+        );
+
+        let mut lockfile = yaml::Hash::new();
+        lockfile.insert(Yaml::from_str(PACKAGES_KEY), packages_yaml(&records));
+        let mut serialized = String::new();
+        YamlEmitter::new(&mut serialized)
+            .dump(&Yaml::Hash(lockfile))
+            .unwrap();
+        let doc = &YamlLoader::load_from_str(&serialized).unwrap()[0];
+        let locked = read_locked_packages(doc);
+        assert_eq!(locked[0].name, "zlib");
+        assert_eq!(locked[0].md5.as_ref().unwrap(), "abc");
+        assert_eq!(locked[0].subdir.as_ref().unwrap(), "linux-64");
+    }
+
+    #[test]
+    fn local_availability() {
+        let dir = tempdir().unwrap();
+        let pkgs = dir.path().join("pkgs");
+        let channel = dir.path().join("channel");
+        create_dir_all(pkgs.join("zlib-1.0-0")).unwrap();
+        create_dir_all(channel.join("linux-64")).unwrap();
+        File::create(channel.join("linux-64").join("xz-5.2.6-0.conda")).unwrap();
+
+        let doc = &YamlLoader::load_from_str(
+            "dependencies:\n  - zlib=1.0=0\n  - xz=5.2.6=0\n  - bzip2=1.0.8=0\n  - python\n",
+        )
+        .unwrap()[0];
+        let locked = exported_packages(doc).unwrap();
+        assert_eq!(locked.len(), 3);
+        assert_eq!(
+            locked[0].filenames(),
+            ["zlib-1.0-0.conda", "zlib-1.0-0.tar.bz2"]
+        );
+
+        let pkgs_dirs = vec![pkgs];
+        let missing = missing_locally(&locked, &pkgs_dirs, Some(&channel));
+        let names: Vec<&str> = missing.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["bzip2"]);
+        assert_eq!(missing_locally(&locked, &pkgs_dirs, None).len(), 2);
+    }
+
+    #[test]
+    fn checksum_verification() {
+        let dir = tempdir().unwrap();
+        let pkgs = dir.path().join("pkgs");
+        create_dir_all(dir.path().join("conda-meta")).unwrap();
+        create_dir_all(&pkgs).unwrap();
+        File::create(pkgs.join("zlib-1.0-0.tar.bz2"))
+            .unwrap()
+            .write_all(b"zlib")
+            .unwrap();
+        let (zlib_md5, _) = file_checksums(&pkgs.join("zlib-1.0-0.tar.bz2")).unwrap();
+        write_meta(dir.path(), &pkgs, "zlib", &zlib_md5);
+        // No cached tarball, so the recorded md5 is compared.
+        write_meta(dir.path(), &pkgs, "xz", "def");
+
+        let mut locked = read_conda_meta(dir.path()).unwrap();
+        assert!(verify_checksums(dir.path(), &locked).unwrap().is_empty());
+
+        // A tampered tarball no longer matches its recorded checksum.
+        File::create(pkgs.join("zlib-1.0-0.tar.bz2"))
+            .unwrap()
+            .write_all(b"evil")
+            .unwrap();
+        // Without a tarball, an installed record that differs from the lockfile is reported.
+        assert_eq!(locked[0].name, "xz");
+        locked[0].md5 = Some("000".to_string());
+        let problems = verify_checksums(dir.path(), &locked).unwrap();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("xz md5 is def"));
+        assert!(problems[1].starts_with("zlib md5"));
+    }
+}
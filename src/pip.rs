@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::prelude::*;
+use std::io::Error as ioError;
+use std::path::{Path, PathBuf};
+
+use glob::glob;
+use serde_json::{self, Value};
+use tempfile::NamedTempFile;
+
+use requirements::{is_index_option, PipEntries, PipEntry};
+use spec::{normalize_pip_name, PipRequirement};
+use {run_command, Result};
+
+/// Pin each locked pip package to the exact artifact it comes from, recording its URL and
+/// sha256.
+///
+/// Packages installed from a URL carry a `direct_url.json` record in their `dist-info`.  For
+/// everything else we ask pip which artifact it would download for the locked version, looking
+/// only at the indexes in `index_options`.
+pub fn lock_artifacts(
+    prefix: &Path,
+    locked: &[PipRequirement],
+    index_options: &[String],
+) -> Result<Vec<PipRequirement>> {
+    let mut artifacts = HashMap::new();
+    let mut unresolved = Vec::new();
+    for req in locked {
+        match direct_url_artifact(prefix, req)? {
+            Some(artifact) => {
+                artifacts.insert(req.normalized_name(), artifact);
+            }
+            None => unresolved.push(req),
+        }
+    }
+    if !unresolved.is_empty() {
+        artifacts.extend(report_artifacts(prefix, &unresolved, index_options)?);
+    }
+
+    let mut hashed = Vec::new();
+    for req in locked {
+        match artifacts.remove(&req.normalized_name()) {
+            Some(artifact) => hashed.push(artifact),
+            None => {
+                let msg = format!("Unable to find the artifact for pip package {}", req);
+                return Err(ioError::other(msg).into());
+            }
+        }
+    }
+    Ok(hashed)
+}
+
+/// A pip section with hashed requirements, installed by pip itself rather than by conda so that
+/// pip runs in hash-checking mode.  pip checks hashes for all of a run's requirements or none,
+/// so whatever can't be hash-checked (editables, local paths, unhashed requirements) is
+/// installed in a second run.
+#[derive(Debug, Default, PartialEq)]
+pub struct HashedInstall {
+    pub hashed: Vec<PipRequirement>,
+    /// Everything else to install, as pip arguments.
+    pub unhashed: Vec<String>,
+    /// Where both runs look for packages.
+    pub index_options: Vec<String>,
+}
+
+impl HashedInstall {
+    /// How to install `entries`, if any of its requirements are hashed.  `base_dir` is the
+    /// lockfile's directory, for relative paths.
+    pub fn from_entries(entries: &PipEntries, base_dir: &Path) -> Option<HashedInstall> {
+        if !entries
+            .requirements()
+            .iter()
+            .any(|req| !req.hashes.is_empty())
+        {
+            return None;
+        }
+        let mut install = HashedInstall {
+            index_options: entries.index_options(base_dir),
+            ..HashedInstall::default()
+        };
+        let local = |target: &str| {
+            if target.starts_with('.') {
+                base_dir.join(target).to_string_lossy().into_owned()
+            } else {
+                target.to_string()
+            }
+        };
+        for entry in &entries.entries {
+            match *entry {
+                PipEntry::Requirement(ref req) if !req.hashes.is_empty() => {
+                    install.hashed.push(req.clone())
+                }
+                PipEntry::Requirement(ref req) => install.unhashed.push(req.to_string()),
+                PipEntry::Editable(ref target) => {
+                    install.unhashed.push("-e".to_string());
+                    install.unhashed.push(local(target));
+                }
+                PipEntry::Url(ref target) => install.unhashed.push(local(target)),
+                PipEntry::Option(ref name, _) if !is_index_option(name) => {
+                    warn!("Ignoring pip option {} when installing with hashes", entry)
+                }
+                // Referenced files are already expanded in place, and constraints only narrow
+                // versions the lockfile already pins exactly.
+                _ => {}
+            }
+        }
+        Some(install)
+    }
+
+    pub fn run(&self, prefix: &Path) -> Result<()> {
+        info!("Installing {} pip packages with hashes", self.hashed.len());
+        install_with_hashes(prefix, &self.hashed, &self.index_options)?;
+        if !self.unhashed.is_empty() {
+            warn!(
+                "Installing pip packages without hash checking: {}",
+                self.unhashed.join(" ")
+            );
+            install(prefix, &self.unhashed, &self.index_options)?;
+        }
+        Ok(())
+    }
+}
+
+/// Install pinned pip packages into an env, refusing any artifact whose hash doesn't match.
+pub fn install_with_hashes(
+    prefix: &Path,
+    requirements: &[PipRequirement],
+    index_options: &[String],
+) -> Result<()> {
+    let mut requirements_file = NamedTempFile::new()?;
+    for req in requirements {
+        writeln!(requirements_file, "{}", req)?;
+    }
+    let requirements_path = requirements_file.path().to_str().unwrap().to_string();
+    let mut args = vec!["-m", "pip", "install", "--no-deps", "--require-hashes"];
+    args.extend(index_options.iter().map(|option| option.as_str()));
+    args.extend(&["-r", &requirements_path]);
+    run_command(python(prefix).to_str().unwrap(), &args)?;
+    Ok(())
+}
+
+/// Install pinned pip packages into an env without resolving their dependencies again.
+/// `targets` are pip arguments, e.g. `requests==2.31.0` or `-e ./src`.
+pub fn install(prefix: &Path, targets: &[String], index_options: &[String]) -> Result<()> {
+    let mut args = vec!["-m", "pip", "install", "--no-deps"];
+    args.extend(index_options.iter().map(|option| option.as_str()));
+    args.extend(targets.iter().map(|target| target.as_str()));
+    run_command(python(prefix).to_str().unwrap(), &args)?;
+    Ok(())
+}
+
+pub fn uninstall(prefix: &Path, names: &[String]) -> Result<()> {
+    let mut args = vec!["-m", "pip", "uninstall", "--yes"];
+    args.extend(names.iter().map(|name| name.as_str()));
+    run_command(python(prefix).to_str().unwrap(), &args)?;
+    Ok(())
+}
+
+/// A distribution pip installed into an env, from its `METADATA`.
+#[derive(Debug, PartialEq)]
+pub struct InstalledDist {
+    pub name: String,
+    pub version: String,
+    pub license: Option<String>,
+}
+
+/// The distributions installed by pip, not by conda, at `prefix`.
+pub fn installed_dists(prefix: &Path) -> Result<Vec<InstalledDist>> {
+    let pattern = prefix.join("lib/python*/site-packages/*.dist-info/METADATA");
+    let mut dists = Vec::new();
+    for path in glob(pattern.to_str().unwrap())? {
+        let path = path?;
+        // conda packages of python libraries ship a dist-info too; conda-meta has those.
+        let installer = read_to_string(path.with_file_name("INSTALLER")).unwrap_or_default();
+        if installer.trim() == "conda" {
+            continue;
+        }
+        if let Some(dist) = parse_metadata(&read_to_string(&path)?) {
+            dists.push(dist);
+        }
+    }
+    dists.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(dists)
+}
+
+// Read the name, version and license from the headers of a core metadata file.  The license is,
+// in order of preference, an SPDX `License-Expression`, a short `License`, or the license
+// classifiers.
+fn parse_metadata(metadata: &str) -> Option<InstalledDist> {
+    let mut headers: Vec<(&str, String)> = Vec::new();
+    for line in metadata.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(|c: char| c.is_whitespace()) {
+            if let Some(&mut (_, ref mut value)) = headers.last_mut() {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some(idx) = line.find(':') {
+            headers.push((&line[..idx], line[idx + 1..].trim().to_string()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|&&(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+
+    // `License` often holds the whole license text, which is no use in a report.
+    let license = header("License")
+        .filter(|license| !license.is_empty() && !license.contains('\n') && license.len() <= 100)
+        .filter(|license| !license.eq_ignore_ascii_case("UNKNOWN"));
+    let classifiers: Vec<&str> = headers
+        .iter()
+        .filter(|&&(key, _)| key.eq_ignore_ascii_case("Classifier"))
+        .filter_map(|(_, value)| value.strip_prefix("License :: "))
+        .map(|classifier| classifier.rsplit(" :: ").next().unwrap_or(classifier))
+        .collect();
+    let license = header("License-Expression")
+        .or(license)
+        .or_else(|| Some(classifiers.join(" OR ")).filter(|license| !license.is_empty()));
+
+    Some(InstalledDist {
+        name: header("Name")?,
+        version: header("Version")?,
+        license,
+    })
+}
+
+fn python(prefix: &Path) -> PathBuf {
+    prefix.join("bin").join("python")
+}
+
+fn direct_url_artifact(prefix: &Path, req: &PipRequirement) -> Result<Option<PipRequirement>> {
+    let version = match req.exact_version() {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    let pattern = prefix.join("lib/python*/site-packages/*.dist-info/direct_url.json");
+    for path in glob(pattern.to_str().unwrap())? {
+        let path = path?;
+        let dist_info = path
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".dist-info"))
+            .unwrap_or("");
+        let matches = match dist_info.rfind('-') {
+            Some(idx) => {
+                normalize_pip_name(&dist_info[..idx]) == req.normalized_name()
+                    && &dist_info[idx + 1..] == version
+            }
+            None => false,
+        };
+        if !matches {
+            continue;
+        }
+        let direct_url: Value = serde_json::from_str(&read_to_string(&path)?)?;
+        let url = direct_url["url"].as_str();
+        // VCS and local directory installs have no archive to hash.
+        let sha256 = archive_sha256(&direct_url["archive_info"]);
+        if let (Some(url), Some(sha256)) = (url, sha256) {
+            return Ok(Some(artifact(&req.name, url, &sha256)));
+        }
+    }
+    Ok(None)
+}
+
+fn report_artifacts(
+    prefix: &Path,
+    requirements: &[&PipRequirement],
+    index_options: &[String],
+) -> Result<HashMap<String, PipRequirement>> {
+    let specs: Vec<String> = requirements.iter().map(|req| req.to_string()).collect();
+    let mut args = vec![
+        "-m",
+        "pip",
+        "install",
+        "--dry-run",
+        "--ignore-installed",
+        "--no-deps",
+        "--quiet",
+        "--report",
+        "-",
+    ];
+    args.extend(index_options.iter().map(|option| option.as_str()));
+    args.extend(specs.iter().map(|spec| spec.as_str()));
+    let output = run_command(python(prefix).to_str().unwrap(), &args)?;
+    parse_report(&String::from_utf8(output.stdout)?)
+}
+
+// Parse the JSON written by `pip install --report`.
+fn parse_report(report: &str) -> Result<HashMap<String, PipRequirement>> {
+    let report: Value = serde_json::from_str(report)?;
+    let mut artifacts = HashMap::new();
+    for install in report["install"].as_array().unwrap_or(&Vec::new()) {
+        let name = install["metadata"]["name"].as_str();
+        let url = install["download_info"]["url"].as_str();
+        let sha256 = archive_sha256(&install["download_info"]["archive_info"]);
+        if let (Some(name), Some(url), Some(sha256)) = (name, url, sha256) {
+            artifacts.insert(normalize_pip_name(name), artifact(name, url, &sha256));
+        }
+    }
+    Ok(artifacts)
+}
+
+// Newer records have a `hashes` mapping, older ones a single `hash` of the form `sha256=<hex>`.
+fn archive_sha256(archive_info: &Value) -> Option<String> {
+    if let Some(sha256) = archive_info["hashes"]["sha256"].as_str() {
+        return Some(sha256.to_string());
+    }
+    archive_info["hash"]
+        .as_str()
+        .and_then(|hash| hash.strip_prefix("sha256="))
+        .map(|sha256| sha256.to_string())
+}
+
+fn artifact(name: &str, url: &str, sha256: &str) -> PipRequirement {
+    PipRequirement {
+        name: name.to_string(),
+        url: Some(url.to_string()),
+        hashes: vec![format!("sha256:{}", sha256)],
+        ..PipRequirement::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, File};
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn report_parsing() {
+        let report = r#"{
+            "version": "1",
+            "install": [{
+                "download_info": {
+                    "url": "https://files.example.com/requests-2.31.0-py3-none-any.whl",
+                    "archive_info": {"hash": "sha256=abc", "hashes": {"sha256": "abc"}}
+                },
+                "metadata": {"name": "Requests", "version": "2.31.0"}
+            }, {
+                "download_info": {
+                    "url": "https://files.example.com/idna-3.4.tar.gz",
+                    "archive_info": {"hash": "sha256=def"}
+                },
+                "metadata": {"name": "idna", "version": "3.4"}
+            }]
+        }"#;
+        let artifacts = parse_report(report).unwrap();
+        assert_eq!(
+            artifacts["requests"].to_string(),
+            "Requests @ https://files.example.com/requests-2.31.0-py3-none-any.whl --hash=sha256:abc"
+        );
+        assert_eq!(artifacts["idna"].hashes, ["sha256:def"]);
+    }
+
+    #[test]
+    fn direct_url_records() {
+        let prefix = tempdir().unwrap();
+        let dist_info = prefix
+            .path()
+            .join("lib/python3.11/site-packages/my_pkg-1.0.dist-info");
+        create_dir_all(&dist_info).unwrap();
+        File::create(dist_info.join("direct_url.json"))
+            .unwrap()
+            .write_all(
+                br#"{"url": "https://example.com/my_pkg-1.0.tar.gz", "archive_info": {"hash": "sha256=abc"}}"#,
+            ).unwrap();
+
+        let req = PipRequirement::parse("my-pkg==1.0").unwrap();
+        let artifact = direct_url_artifact(prefix.path(), &req).unwrap().unwrap();
+        assert_eq!(
+            artifact.url.unwrap(),
+            "https://example.com/my_pkg-1.0.tar.gz"
+        );
+        assert_eq!(artifact.hashes, ["sha256:abc"]);
+
+        let req = PipRequirement::parse("my-pkg==2.0").unwrap();
+        assert!(direct_url_artifact(prefix.path(), &req).unwrap().is_none());
+    }
+
+    #[test]
+    fn metadata_licenses() {
+        let dist = parse_metadata(
+            "Metadata-Version: 2.1
+Name: requests
+Version: 2.31.0
+License: Apache 2.0
+Classifier: License :: OSI Approved :: Apache Software License
+
+Requests is an HTTP library.
+License: not a header
+",
+        )
+        .unwrap();
+        assert_eq!(dist.name, "requests");
+        assert_eq!(dist.version, "2.31.0");
+        assert_eq!(dist.license.as_deref(), Some("Apache 2.0"));
+
+        // The full license text is replaced by the classifiers.
+        let dist = parse_metadata(
+            "Name: idna
+Version: 3.4
+License: Copyright (c) 2013-2023, Kim Davies
+        All rights reserved.
+Classifier: License :: OSI Approved :: BSD License
+Classifier: Programming Language :: Python
+",
+        )
+        .unwrap();
+        assert_eq!(dist.license.as_deref(), Some("BSD License"));
+
+        let dist = parse_metadata(
+            "Name: attrs\nVersion: 23.1.0\nLicense-Expression: MIT\nLicense: UNKNOWN\n",
+        )
+        .unwrap();
+        assert_eq!(dist.license.as_deref(), Some("MIT"));
+
+        let dist = parse_metadata("Name: mystery\nVersion: 0.1\nLicense: UNKNOWN\n").unwrap();
+        assert_eq!(dist.license, None);
+        assert_eq!(parse_metadata("Version: 0.1\n"), None);
+    }
+
+    #[test]
+    fn installed_by_pip() {
+        let prefix = tempdir().unwrap();
+        let site_packages = prefix.path().join("lib/python3.11/site-packages");
+        let dists = [("six", "1.16.0", "pip"), ("numpy", "1.26.0", "conda")];
+        for &(name, version, installer) in dists.iter() {
+            let dist_info = site_packages.join(format!("{}-{}.dist-info", name, version));
+            create_dir_all(&dist_info).unwrap();
+            let metadata = format!("Name: {}\nVersion: {}\nLicense: MIT\n", name, version);
+            File::create(dist_info.join("METADATA"))
+                .unwrap()
+                .write_all(metadata.as_bytes())
+                .unwrap();
+            File::create(dist_info.join("INSTALLER"))
+                .unwrap()
+                .write_all(installer.as_bytes())
+                .unwrap();
+        }
+        let dists = installed_dists(prefix.path()).unwrap();
+        assert_eq!(dists.len(), 1);
+        assert_eq!(dists[0].name, "six");
+        assert_eq!(dists[0].version, "1.16.0");
+    }
+}
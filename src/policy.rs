@@ -0,0 +1,387 @@
+use std::error::Error;
+use std::fs::read_to_string;
+use std::io::Error as ioError;
+use std::path::Path;
+
+use toml::Value;
+use yaml_rust::Yaml;
+
+use channels;
+use packages::{exported_packages, read_locked_packages};
+use requirements::read_pip_entries;
+use spec::normalize_pip_name;
+use version::{conda_version_matches, glob_matches, pip_version_matches};
+use Result;
+
+/// A package that must never be locked.  Every field that is given has to match.
+#[derive(Debug, Default, PartialEq)]
+pub struct DenyRule {
+    /// Package name, conda or pip, may contain `*` wildcards.
+    pub name: Option<String>,
+    /// Version constraint, e.g. `<3.0.7`.  conda ordering for conda packages, PEP 440 for pip.
+    pub version: Option<String>,
+    /// License, may contain `*` wildcards, e.g. `GPL*`.  Case insensitive.
+    pub license: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Which packages may go in a lockfile, read from a TOML policy file:
+///
+/// ```toml
+/// [[deny]]
+/// name = "openssl"
+/// version = "<3.0.7"
+/// reason = "CVE-2022-3602"
+///
+/// [[deny]]
+/// license = "GPL*"
+///
+/// [allow]
+/// channels = ["conda-forge"]
+/// ```
+#[derive(Debug, Default, PartialEq)]
+pub struct Policy {
+    pub deny: Vec<DenyRule>,
+    /// Channels packages may come from.  Any channel when not given.
+    pub allowed_channels: Option<Vec<String>>,
+}
+
+/// A package, as far as the policy is concerned.
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub license: Option<String>,
+    pub pip: bool,
+}
+
+impl DenyRule {
+    fn matches(&self, package: &Package) -> Result<bool> {
+        if let Some(ref name) = self.name {
+            let matches = if package.pip {
+                glob_matches(
+                    &normalize_pip_name(name),
+                    &normalize_pip_name(&package.name),
+                )
+            } else {
+                glob_matches(&name.to_lowercase(), &package.name)
+            };
+            if !matches {
+                return Ok(false);
+            }
+        }
+        if let Some(ref constraint) = self.version {
+            let matches = if package.pip {
+                pip_version_matches(constraint, &package.version)?
+            } else {
+                conda_version_matches(constraint, &package.version)?
+            };
+            if !matches {
+                return Ok(false);
+            }
+        }
+        if let Some(ref license) = self.license {
+            match package.license {
+                Some(ref found) if glob_matches(&license.to_lowercase(), &found.to_lowercase()) => {
+                }
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(ref name) = self.name {
+            parts.push(name.clone());
+        }
+        if let Some(ref version) = self.version {
+            parts.push(version.clone());
+        }
+        if let Some(ref license) = self.license {
+            parts.push(format!("license {}", license));
+        }
+        let mut description = parts.join(" ");
+        if let Some(ref reason) = self.reason {
+            description.push_str(&format!(": {}", reason));
+        }
+        description
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Policy> {
+        let data = match read_to_string(path) {
+            Ok(data) => data,
+            Err(err) => {
+                let msg = format!("Unable to read policy {}: {}", path.display(), err);
+                return Err(ioError::other(msg).into());
+            }
+        };
+        match data.parse::<Value>() {
+            Ok(value) => Policy::from_toml(&value, path),
+            Err(err) => Err(ioError::other(format!("{}: {}", path.display(), err)).into()),
+        }
+    }
+
+    fn from_toml(value: &Value, path: &Path) -> Result<Policy> {
+        let invalid = |key: &str, expected: &str| -> Box<dyn Error> {
+            let msg = format!("{}: `{}` must be {}", path.display(), key, expected);
+            ioError::other(msg).into()
+        };
+        let string = |value: &Value, key: &str| -> Result<String> {
+            match value.as_str() {
+                Some(value) => Ok(value.to_string()),
+                None => Err(invalid(key, "a string")),
+            }
+        };
+
+        let mut policy = Policy::default();
+        for (key, value) in value.as_table().unwrap().iter() {
+            match key.as_str() {
+                "deny" => {
+                    let rules = value
+                        .as_array()
+                        .ok_or_else(|| invalid(key, "a list of tables"))?;
+                    for rule in rules {
+                        let rule = rule
+                            .as_table()
+                            .ok_or_else(|| invalid(key, "a list of tables"))?;
+                        let mut deny = DenyRule::default();
+                        for (key, value) in rule.iter() {
+                            let setting = Some(string(value, key)?);
+                            match key.as_str() {
+                                "name" => deny.name = setting,
+                                "version" => deny.version = setting,
+                                "license" => deny.license = setting,
+                                "reason" => deny.reason = setting,
+                                _ => return Err(unknown_key(path, &format!("deny.{}", key))),
+                            }
+                        }
+                        if deny.name.is_none() && deny.license.is_none() {
+                            return Err(invalid("deny", "given a `name` or a `license`"));
+                        }
+                        if deny.version.is_some() && deny.name.is_none() {
+                            return Err(invalid("deny.version", "given with a `name`"));
+                        }
+                        policy.deny.push(deny);
+                    }
+                }
+                "allow" => {
+                    let allow = value.as_table().ok_or_else(|| invalid(key, "a table"))?;
+                    for (key, value) in allow.iter() {
+                        match key.as_str() {
+                            "channels" => {
+                                let expected = "a list of channels";
+                                let mut allowed = Vec::new();
+                                for channel in
+                                    value.as_array().ok_or_else(|| invalid(key, expected))?
+                                {
+                                    allowed.push(string(channel, key)?);
+                                }
+                                policy.allowed_channels = Some(allowed);
+                            }
+                            _ => return Err(unknown_key(path, &format!("allow.{}", key))),
+                        }
+                    }
+                }
+                _ => return Err(unknown_key(path, key)),
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Why the policy doesn't allow a package, if it doesn't.  A package without a known license
+    /// is only denied by name and version.
+    pub fn denials(&self, package: &Package) -> Result<Vec<String>> {
+        let mut denials = Vec::new();
+        for rule in self.deny.iter() {
+            if rule.matches(package)? {
+                denials.push(format!("denied ({})", rule.describe()));
+            }
+        }
+        Ok(denials)
+    }
+
+    /// Every way a lockfile breaks the policy.  `base_dir` is the lockfile's directory, for any
+    /// requirements files it references.
+    pub fn check(&self, lockfile: &Yaml, base_dir: &Path) -> Result<Vec<String>> {
+        let mut violations = Vec::new();
+        let mut locked = Vec::new();
+        let mut conda = read_locked_packages(lockfile);
+        if conda.is_empty() {
+            conda = exported_packages(lockfile)?;
+        }
+        for record in conda {
+            locked.push(Package {
+                name: record.name,
+                version: record.version,
+                license: record.license,
+                pip: false,
+            });
+        }
+        // Lockfiles pin every pip package, to a version or to an artifact URL.
+        for req in read_pip_entries(lockfile, base_dir)?.requirements() {
+            if let Some(version) = req.exact_version() {
+                locked.push(Package {
+                    name: req.name.clone(),
+                    version: version.to_string(),
+                    license: None,
+                    pip: true,
+                });
+            }
+        }
+
+        for package in locked.iter() {
+            for denial in self.denials(package)? {
+                violations.push(format!(
+                    "{} {} is {}",
+                    package.name, package.version, denial
+                ));
+            }
+        }
+        if let Some(ref allowed) = self.allowed_channels {
+            let allowed = channels::allowed(allowed.iter().map(|channel| channel.as_str()));
+            violations.extend(channels::check(lockfile, &allowed));
+        }
+        Ok(violations)
+    }
+}
+
+// Typos would otherwise silently weaken the policy.
+fn unknown_key(path: &Path, key: &str) -> Box<dyn Error> {
+    ioError::other(format!(
+        "{}: unknown policy setting `{}`",
+        path.display(),
+        key
+    ))
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use yaml_rust::YamlLoader;
+
+    fn parse(data: &str) -> Result<Policy> {
+        Policy::from_toml(&data.parse::<Value>().unwrap(), Path::new("policy.toml"))
+    }
+
+    #[test]
+    fn parse_policy() {
+        let policy = parse(
+            "[[deny]]
+name = \"openssl\"
+version = \"<3.0.7\"
+reason = \"CVE-2022-3602\"
+
+[[deny]]
+license = \"GPL*\"
+
+[allow]
+channels = [\"conda-forge\"]
+",
+        )
+        .unwrap();
+        assert_eq!(policy.deny.len(), 2);
+        assert_eq!(policy.deny[0].version.as_deref(), Some("<3.0.7"));
+        assert_eq!(policy.deny[1].license.as_deref(), Some("GPL*"));
+        assert_eq!(
+            policy.allowed_channels,
+            Some(vec!["conda-forge".to_string()])
+        );
+
+        assert!(parse("[[deny]]\nreason = \"nothing to match\"").is_err());
+        assert!(parse("[[deny]]\nversion = \"<1\"\nlicense = \"GPL*\"").is_err());
+        assert!(parse("[[deny]]\nnmae = \"openssl\"").is_err());
+        assert!(parse("[allow]\nchannel = [\"conda-forge\"]").is_err());
+        assert!(parse("[[allow]]\nchannels = []").is_err());
+        assert!(parse("[allow]\nlicenses = [\"MIT\"]").is_err());
+    }
+
+    #[test]
+    fn license_denials() {
+        let policy = parse("[[deny]]\nlicense = \"GPL*\"\nreason = \"copyleft\"").unwrap();
+        let package = |license: Option<&str>| Package {
+            name: "readline".to_string(),
+            version: "8.2".to_string(),
+            license: license.map(|license| license.to_string()),
+            pip: false,
+        };
+        assert!(policy.denials(&package(Some("MIT"))).unwrap().is_empty());
+        assert_eq!(
+            policy.denials(&package(Some("gpl-3.0-only"))).unwrap(),
+            ["denied (license GPL*: copyleft)"]
+        );
+        // Unknown licenses are for the license report to flag.
+        assert!(policy.denials(&package(None)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_lockfile() {
+        let policy = parse(
+            "[[deny]]
+name = \"openssl\"
+version = \"<3.0.7\"
+reason = \"CVE-2022-3602\"
+
+[[deny]]
+license = \"GPL*\"
+
+[[deny]]
+name = \"PyYAML\"
+version = \"<5.4\"
+
+[allow]
+channels = [\"conda-forge\"]
+",
+        )
+        .unwrap();
+        let lockfile = &YamlLoader::load_from_str(
+            "dependencies:
+  - openssl=3.0.5=h166bdaf_0
+  - readline=8.2=h8228510_1
+  - zlib=1.2.13=hd590300_5
+  - pip:
+    - pyyaml==5.3.1
+    - requests==2.31.0
+packages:
+  openssl:
+    version: 3.0.5
+    build: h166bdaf_0
+    channel: conda-forge
+    license: Apache-2.0
+  readline:
+    version: '8.2'
+    build: h8228510_1
+    channel: conda-forge
+    license: GPL-3.0-only
+  zlib:
+    version: 1.2.13
+    build: hd590300_5
+    channel: pkgs/main
+    license: Zlib
+",
+        )
+        .unwrap()[0];
+        let violations = policy.check(lockfile, Path::new("")).unwrap();
+        assert_eq!(
+            violations,
+            [
+                "openssl 3.0.5 is denied (openssl <3.0.7: CVE-2022-3602)",
+                "readline 8.2 is denied (license GPL*)",
+                "pyyaml 5.3.1 is denied (PyYAML <5.4)",
+                "zlib 1.2.13 hd590300_5 is from pkgs/main, not one of conda-forge",
+            ]
+        );
+
+        // Lockfiles without a packages section are checked by name and version.
+        let exported =
+            &YamlLoader::load_from_str("dependencies:\n  - openssl=3.0.8=h0b41bf4_0\n").unwrap()[0];
+        assert!(parse("[[deny]]\nname = \"openssl\"\nversion = \"<3.0.7\"")
+            .unwrap()
+            .check(exported, Path::new(""))
+            .unwrap()
+            .is_empty());
+    }
+}
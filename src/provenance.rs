@@ -0,0 +1,195 @@
+use std::fs::{read_to_string, remove_file, File};
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{self, Map, Value};
+
+use Result;
+
+/// Where provenance is stored, relative to the env's prefix.  Not in conda-meta, where conda
+/// reads every JSON file as a package record.
+pub const PROVENANCE_PATH: &str = "conda-lockfile.json";
+// Where envs created by earlier versions stored it.
+const LEGACY_PROVENANCE_PATH: &str = "conda-meta/conda-lockfile.json";
+
+/// How and from what an env was created.
+#[derive(Debug, Default, PartialEq)]
+pub struct Provenance {
+    /// Hash of the lockfile the env was built from.
+    pub lockfile_hash: String,
+    /// The depfile hash recorded in the lockfile at freeze time.
+    pub depfile_hash: Option<String>,
+    pub lockfile_path: String,
+    /// Version of conda-lockfile that created the env.
+    pub tool_version: String,
+    /// RFC 3339, UTC.
+    pub created: String,
+    pub solver: Option<String>,
+}
+
+impl Provenance {
+    pub fn to_json(&self) -> Value {
+        let mut data = Map::new();
+        let mut insert = |key: &str, value: Option<&String>| {
+            data.insert(
+                key.to_string(),
+                value.map_or(Value::Null, |value| Value::String(value.clone())),
+            );
+        };
+        insert("lockfile_hash", Some(&self.lockfile_hash));
+        insert("depfile_hash", self.depfile_hash.as_ref());
+        insert("lockfile_path", Some(&self.lockfile_path));
+        insert("tool_version", Some(&self.tool_version));
+        insert("created", Some(&self.created));
+        insert("solver", self.solver.as_ref());
+        Value::Object(data)
+    }
+
+    fn from_json(data: &Value) -> Provenance {
+        let field = |key: &str| data[key].as_str().map(|value| value.to_string());
+        Provenance {
+            lockfile_hash: field("lockfile_hash").unwrap_or_default(),
+            depfile_hash: field("depfile_hash"),
+            lockfile_path: field("lockfile_path").unwrap_or_default(),
+            tool_version: field("tool_version").unwrap_or_default(),
+            created: field("created").unwrap_or_default(),
+            solver: field("solver"),
+        }
+    }
+
+    /// The fields in display order, for printing.
+    pub fn fields(&self) -> Vec<(&str, &str)> {
+        vec![
+            ("lockfile", self.lockfile_path.as_str()),
+            ("lockfile hash", self.lockfile_hash.as_str()),
+            ("depfile hash", or_unknown(&self.depfile_hash)),
+            ("created", self.created.as_str()),
+            ("created by", self.tool_version.as_str()),
+            ("solver", or_unknown(&self.solver)),
+        ]
+    }
+
+    pub fn write(&self, prefix: &Path) -> Result<()> {
+        let mut file = File::create(prefix.join(PROVENANCE_PATH))?;
+        file.write_all(serde_json::to_string_pretty(&self.to_json())?.as_bytes())?;
+        // An updated env shouldn't keep the record from before the update.
+        let legacy = prefix.join(LEGACY_PROVENANCE_PATH);
+        if legacy.exists() {
+            remove_file(legacy)?;
+        }
+        Ok(())
+    }
+
+    /// Read an env's provenance.  Envs created by older versions have none, or have it in
+    /// conda-meta.
+    pub fn read(prefix: &Path) -> Result<Option<Provenance>> {
+        let path = match [PROVENANCE_PATH, LEGACY_PROVENANCE_PATH]
+            .iter()
+            .map(|path| prefix.join(path))
+            .find(|path| path.exists())
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let data: Value = serde_json::from_str(&read_to_string(path)?)?;
+        Ok(Some(Provenance::from_json(&data)))
+    }
+}
+
+fn or_unknown(value: &Option<String>) -> &str {
+    value.as_ref().map_or("unknown", |value| value.as_str())
+}
+
+/// The current time as an RFC 3339 timestamp, e.g. `2024-01-31T12:00:00Z`.
+pub fn now_rfc3339() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    rfc3339(seconds)
+}
+
+fn rfc3339(unix_seconds: u64) -> String {
+    let days = (unix_seconds / 86400) as i64;
+    let seconds = unix_seconds % 86400;
+    // Civil date from days since 1970-01-01, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, read_dir, write};
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_706_702_400), "2024-01-31T12:00:00Z");
+    }
+
+    #[test]
+    fn round_trip() {
+        let prefix = tempdir().unwrap();
+        create_dir_all(prefix.path().join("conda-meta")).unwrap();
+        assert!(Provenance::read(prefix.path()).unwrap().is_none());
+
+        let provenance = Provenance {
+            lockfile_hash: "abc".to_string(),
+            depfile_hash: Some("def".to_string()),
+            lockfile_path: "/src/app/deps.Linux.lock.yml".to_string(),
+            tool_version: "0.7.0".to_string(),
+            created: "2024-01-31T12:00:00Z".to_string(),
+            solver: None,
+        };
+        provenance.write(prefix.path()).unwrap();
+        assert!(prefix.path().join("conda-lockfile.json").is_file());
+        assert_eq!(
+            read_dir(prefix.path().join("conda-meta")).unwrap().count(),
+            0
+        );
+        let read = Provenance::read(prefix.path()).unwrap().unwrap();
+        assert_eq!(read, provenance);
+        assert_eq!(read.fields()[5], ("solver", "unknown"));
+    }
+
+    #[test]
+    fn legacy_location() {
+        let prefix = tempdir().unwrap();
+        create_dir_all(prefix.path().join("conda-meta")).unwrap();
+        let legacy = prefix.path().join(LEGACY_PROVENANCE_PATH);
+        write(
+            &legacy,
+            r#"{"lockfile_hash": "abc", "tool_version": "0.6.0"}"#,
+        )
+        .unwrap();
+        let read = Provenance::read(prefix.path()).unwrap().unwrap();
+        assert_eq!(read.lockfile_hash, "abc");
+        assert_eq!(read.depfile_hash, None);
+
+        read.write(prefix.path()).unwrap();
+        assert!(!legacy.exists());
+        assert_eq!(Provenance::read(prefix.path()).unwrap().unwrap(), read);
+    }
+}
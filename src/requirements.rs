@@ -0,0 +1,465 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::read_to_string;
+use std::io::Error as ioError;
+use std::path::{Path, PathBuf};
+
+use yaml_rust::Yaml;
+
+use spec::PipRequirement;
+use Result;
+
+// Options that take a value, with their short forms.
+const VALUE_OPTIONS: &[(&str, &str)] = &[
+    ("--requirement", "-r"),
+    ("--constraint", "-c"),
+    ("--editable", "-e"),
+    ("--index-url", "-i"),
+    ("--extra-index-url", ""),
+    ("--find-links", "-f"),
+    ("--trusted-host", ""),
+    ("--no-binary", ""),
+    ("--only-binary", ""),
+];
+
+// Options that choose where pip downloads packages from.
+const INDEX_OPTIONS: &[&str] = &[
+    "--index-url",
+    "--extra-index-url",
+    "--no-index",
+    "--find-links",
+    "--trusted-host",
+];
+
+/// A single line from the pip section of a depfile, or from a requirements file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PipEntry {
+    Requirement(PipRequirement),
+    RequirementsFile(String),
+    ConstraintsFile(String),
+    Editable(String),
+    /// A VCS URL, archive URL or local path, e.g. `git+https://github.com/org/pkg.git#egg=pkg`.
+    Url(String),
+    /// Any other pip option, e.g. `--extra-index-url https://pypi.internal/simple`.
+    Option(String, Option<String>),
+}
+
+impl PipEntry {
+    pub fn parse(line: &str) -> Result<PipEntry> {
+        let line = line.trim();
+        if line.starts_with('-') {
+            return parse_option(line);
+        }
+        if is_url(line) {
+            return Ok(PipEntry::Url(line.to_string()));
+        }
+        // Per-requirement options such as `--hash` trail the requirement itself.
+        let (requirement, options) = match line.find(" -") {
+            Some(idx) => (&line[..idx], &line[idx..]),
+            None => (line, ""),
+        };
+        let mut req = PipRequirement::parse(requirement)?;
+        for option in options.split_whitespace() {
+            match option.strip_prefix("--hash=") {
+                Some(hash) => req.hashes.push(hash.to_string()),
+                None => debug!("Ignoring option {:?} on {:?}", option, line),
+            }
+        }
+        Ok(PipEntry::Requirement(req))
+    }
+}
+
+impl fmt::Display for PipEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PipEntry::Requirement(ref req) => write!(f, "{}", req),
+            PipEntry::RequirementsFile(ref path) => write!(f, "-r {}", path),
+            PipEntry::ConstraintsFile(ref path) => write!(f, "-c {}", path),
+            PipEntry::Editable(ref target) => write!(f, "-e {}", target),
+            PipEntry::Url(ref url) => write!(f, "{}", url),
+            PipEntry::Option(ref name, Some(ref value)) => write!(f, "{} {}", name, value),
+            PipEntry::Option(ref name, None) => write!(f, "{}", name),
+        }
+    }
+}
+
+fn parse_option(line: &str) -> Result<PipEntry> {
+    let (flag, value) = match line.find(|c: char| c == '=' || c.is_whitespace()) {
+        Some(idx) => (&line[..idx], Some(line[idx + 1..].trim())),
+        None => (line, None),
+    };
+    // Short options may be glued to their value, e.g. `-rrequirements.txt`.
+    let (flag, value) = match VALUE_OPTIONS.iter().find(|&&(_, short)| {
+        !short.is_empty()
+            && flag.starts_with(short)
+            && flag.len() > short.len()
+            && !flag.starts_with("--")
+    }) {
+        Some(&(_, short)) => (short, Some(&flag[short.len()..])),
+        None => (flag, value),
+    };
+    let name = match VALUE_OPTIONS
+        .iter()
+        .find(|&&(long, short)| flag == long || flag == short)
+    {
+        Some(&(long, _)) => long,
+        None => {
+            return Ok(PipEntry::Option(
+                flag.to_string(),
+                value.map(|v| v.to_string()),
+            ))
+        }
+    };
+    let value = match value {
+        Some(value) if !value.is_empty() => value.to_string(),
+        _ => return Err(ioError::other(format!("Pip option {} requires a value", name)).into()),
+    };
+    Ok(match name {
+        "--requirement" => PipEntry::RequirementsFile(value),
+        "--constraint" => PipEntry::ConstraintsFile(value),
+        "--editable" => PipEntry::Editable(value),
+        _ => PipEntry::Option(name.to_string(), Some(value)),
+    })
+}
+
+// `pkg @ https://...` is a named requirement, so only look at the first token.
+fn is_url(line: &str) -> bool {
+    let first = line.split_whitespace().next().unwrap_or("");
+    first.contains("://")
+        || ["git+", "hg+", "svn+", "bzr+", "file:", ".", "/"]
+            .iter()
+            .any(|prefix| first.starts_with(prefix))
+}
+
+/// Whether `--name` chooses where pip downloads packages from, e.g. `--extra-index-url`.
+pub fn is_index_option(name: &str) -> bool {
+    INDEX_OPTIONS.contains(&name)
+}
+
+// Whether pip needs the network to fetch `target`.
+pub fn is_remote(target: &str) -> bool {
+    target.contains("://") && !target.starts_with("file:")
+}
+
+/// The pip entries of a depfile, with any referenced requirements files expanded in place.
+#[derive(Debug, Default)]
+pub struct PipEntries {
+    pub entries: Vec<PipEntry>,
+    /// Requirements and constraints files that were read, in order.
+    pub files: Vec<PathBuf>,
+}
+
+impl PipEntries {
+    pub fn requirements(&self) -> Vec<&PipRequirement> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match *entry {
+                PipEntry::Requirement(ref req) => Some(req),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The entries pip would have to download from an index or a remote URL.  A local
+    /// `--find-links` directory is assumed to hold a wheel for every requirement.
+    pub fn unavailable_offline(&self) -> Vec<&PipEntry> {
+        let local_links = self.entries.iter().any(|entry| match *entry {
+            PipEntry::Option(ref name, Some(ref value)) => {
+                name == "--find-links" && !is_remote(value)
+            }
+            _ => false,
+        });
+        self.entries
+            .iter()
+            .filter(|entry| match **entry {
+                PipEntry::Requirement(ref req) => match req.url {
+                    Some(ref url) => is_remote(url),
+                    None => !local_links,
+                },
+                PipEntry::Url(ref target) | PipEntry::Editable(ref target) => is_remote(target),
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// The options that tell pip where to look for packages, as command line arguments.  A
+    /// relative local `--find-links` directory is resolved against `base_dir`.
+    pub fn index_options(&self, base_dir: &Path) -> Vec<String> {
+        let mut args = Vec::new();
+        for entry in &self.entries {
+            if let PipEntry::Option(ref name, ref value) = *entry {
+                if !is_index_option(name) {
+                    continue;
+                }
+                args.push(name.clone());
+                if let Some(ref value) = *value {
+                    if name == "--find-links" && !value.contains("://") {
+                        args.push(base_dir.join(value).to_string_lossy().into_owned());
+                    } else {
+                        args.push(value.clone());
+                    }
+                }
+            }
+        }
+        args
+    }
+
+    fn extend(
+        &mut self,
+        lines: &[&str],
+        base_dir: &Path,
+        seen: &mut HashSet<PathBuf>,
+    ) -> Result<()> {
+        for line in lines {
+            let entry = PipEntry::parse(line)?;
+            let referenced = match entry {
+                PipEntry::RequirementsFile(ref path) => Some((base_dir.join(path), true)),
+                PipEntry::ConstraintsFile(ref path) => Some((base_dir.join(path), false)),
+                _ => None,
+            };
+            self.entries.push(entry);
+            if let Some((path, is_requirements)) = referenced {
+                self.read_file(&path, is_requirements, seen)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_file(
+        &mut self,
+        path: &Path,
+        is_requirements: bool,
+        seen: &mut HashSet<PathBuf>,
+    ) -> Result<()> {
+        if !seen.insert(path.to_path_buf()) {
+            return Ok(());
+        }
+        let data = match read_to_string(path) {
+            Ok(data) => data,
+            Err(err) => {
+                let msg = format!("Unable to read {}: {}", path.display(), err);
+                return Err(ioError::other(msg).into());
+            }
+        };
+        self.files.push(path.to_path_buf());
+
+        let lines = requirement_lines(&data);
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        if is_requirements {
+            return self.extend(&lines, base_dir, seen);
+        }
+        // Constraints only restrict versions; they don't add requirements.  Nested files still
+        // matter for hashing.
+        let mut constraints = PipEntries::default();
+        constraints.extend(&lines, base_dir, seen)?;
+        self.files.extend(constraints.files);
+        Ok(())
+    }
+}
+
+/// Read the pip section of a depfile, resolving referenced files relative to `base_dir`.
+pub fn read_pip_entries(doc: &Yaml, base_dir: &Path) -> Result<PipEntries> {
+    let mut pip_entries = PipEntries::default();
+    let mut seen = HashSet::new();
+    if let Some(deps) = doc["dependencies"].as_vec() {
+        for pips in deps.iter().filter_map(|d| d["pip"].as_vec()) {
+            let lines: Vec<&str> = pips.iter().filter_map(|pip| pip.as_str()).collect();
+            pip_entries.extend(&lines, base_dir, &mut seen)?;
+        }
+    }
+    Ok(pip_entries)
+}
+
+// Split a requirements file into logical lines, joining continuations and dropping comments.
+fn requirement_lines(data: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for line in data.lines() {
+        if let Some(continued) = line.strip_suffix('\\') {
+            current.push_str(continued);
+            continue;
+        }
+        current.push_str(line);
+        // `#` only starts a comment at the beginning of a line or after whitespace.
+        let comment = current
+            .match_indices('#')
+            .map(|(idx, _)| idx)
+            .find(|&idx| idx == 0 || current[..idx].ends_with(char::is_whitespace));
+        let without_comment = match comment {
+            Some(idx) => &current[..idx],
+            None => current.as_str(),
+        };
+        if !without_comment.trim().is_empty() {
+            lines.push(without_comment.trim().to_string());
+        }
+        current.clear();
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir, File};
+    use std::io::Write;
+
+    use tempfile::tempdir;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn parse_entries() {
+        assert_eq!(
+            PipEntry::parse("-r requirements.txt").unwrap(),
+            PipEntry::RequirementsFile("requirements.txt".to_string())
+        );
+        assert_eq!(
+            PipEntry::parse("--requirement=reqs/base.txt").unwrap(),
+            PipEntry::RequirementsFile("reqs/base.txt".to_string())
+        );
+        assert_eq!(
+            PipEntry::parse("-cconstraints.txt").unwrap(),
+            PipEntry::ConstraintsFile("constraints.txt".to_string())
+        );
+        assert_eq!(
+            PipEntry::parse("-e ./local").unwrap(),
+            PipEntry::Editable("./local".to_string())
+        );
+        assert_eq!(
+            PipEntry::parse("--extra-index-url https://pypi.internal/simple").unwrap(),
+            PipEntry::Option(
+                "--extra-index-url".to_string(),
+                Some("https://pypi.internal/simple".to_string())
+            )
+        );
+        assert_eq!(
+            PipEntry::parse("--pre").unwrap(),
+            PipEntry::Option("--pre".to_string(), None)
+        );
+        assert_eq!(
+            PipEntry::parse("git+https://github.com/org/pkg.git@v1#egg=pkg").unwrap(),
+            PipEntry::Url("git+https://github.com/org/pkg.git@v1#egg=pkg".to_string())
+        );
+        match PipEntry::parse("pkg @ https://example.com/pkg-1.0.tar.gz").unwrap() {
+            PipEntry::Requirement(req) => assert_eq!(req.name, "pkg"),
+            entry => panic!("Unexpected entry {}", entry),
+        }
+        match PipEntry::parse("requests==2.31.0 --hash=sha256:abc --hash=sha256:def").unwrap() {
+            PipEntry::Requirement(req) => {
+                assert_eq!(req.specifier.unwrap(), "==2.31.0");
+                assert_eq!(req.hashes, ["sha256:abc", "sha256:def"]);
+            }
+            entry => panic!("Unexpected entry {}", entry),
+        }
+        assert!(PipEntry::parse("-r").is_err());
+    }
+
+    #[test]
+    fn comments_and_continuations() {
+        let lines =
+            requirement_lines("# header\nrequests>=2 \\\n  ,<3  # pinned\n\nurl#frag # vcs\n");
+        assert_eq!(lines, ["requests>=2   ,<3", "url#frag"]);
+    }
+
+    #[test]
+    fn entries_available_offline() {
+        let doc = &YamlLoader::load_from_str(
+            "dependencies:
+  - pip:
+    - requests==2.31.0
+    - pkg @ file:///wheels/pkg-1.0-py3-none-any.whl
+    - ./vendor/tool
+    - git+https://github.com/org/pkg.git#egg=pkg
+",
+        )
+        .unwrap()[0];
+        let entries = read_pip_entries(doc, Path::new("")).unwrap();
+        let unavailable: Vec<String> = entries
+            .unavailable_offline()
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+        assert_eq!(
+            unavailable,
+            [
+                "requests==2.31.0",
+                "git+https://github.com/org/pkg.git#egg=pkg"
+            ]
+        );
+
+        let doc = &YamlLoader::load_from_str(
+            "dependencies:\n  - pip:\n    - --find-links /wheels\n    - requests==2.31.0\n",
+        )
+        .unwrap()[0];
+        let entries = read_pip_entries(doc, Path::new("")).unwrap();
+        assert!(entries.unavailable_offline().is_empty());
+    }
+
+    #[test]
+    fn index_options() {
+        let doc = &YamlLoader::load_from_str(
+            "dependencies:
+  - pip:
+    - -i https://pypi.internal/simple
+    - --extra-index-url https://mirror.internal/simple
+    - --find-links wheels
+    - --find-links https://downloads.internal/wheels
+    - --trusted-host pypi.internal
+    - --pre
+    - requests==2.31.0
+",
+        )
+        .unwrap()[0];
+        let entries = read_pip_entries(doc, Path::new("")).unwrap();
+        assert_eq!(
+            entries.index_options(Path::new("/work")),
+            [
+                "--index-url",
+                "https://pypi.internal/simple",
+                "--extra-index-url",
+                "https://mirror.internal/simple",
+                "--find-links",
+                "/work/wheels",
+                "--find-links",
+                "https://downloads.internal/wheels",
+                "--trusted-host",
+                "pypi.internal",
+            ]
+        );
+    }
+
+    #[test]
+    fn read_referenced_files() {
+        let dir = tempdir().unwrap();
+        create_dir(dir.path().join("reqs")).unwrap();
+        let mut base = File::create(dir.path().join("reqs").join("base.txt")).unwrap();
+        base.write_all(b"requests>=2\n-c constraints.txt\n")
+            .unwrap();
+        let mut constraints =
+            File::create(dir.path().join("reqs").join("constraints.txt")).unwrap();
+        constraints.write_all(b"urllib3<2\n").unwrap();
+
+        let doc = &YamlLoader::load_from_str(
+            "dependencies:\n  - python\n  - pip:\n    - --index-url https://pypi.internal/simple\n    - -r reqs/base.txt\n    - flask\n",
+        ).unwrap()[0];
+        let pip_entries = read_pip_entries(doc, dir.path()).unwrap();
+        let names: Vec<&str> = pip_entries
+            .requirements()
+            .iter()
+            .map(|req| req.name.as_str())
+            .collect();
+        assert_eq!(names, ["requests", "flask"]);
+        assert_eq!(
+            pip_entries.files,
+            [
+                dir.path().join("reqs/base.txt"),
+                dir.path().join("reqs").join("constraints.txt"),
+            ]
+        );
+
+        let doc = &YamlLoader::load_from_str("dependencies:\n  - pip:\n    - -r missing.txt\n")
+            .unwrap()[0];
+        assert!(read_pip_entries(doc, dir.path()).is_err());
+    }
+}
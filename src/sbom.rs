@@ -0,0 +1,565 @@
+use std::path::Path;
+
+use serde_json::{Map, Value};
+use sha1;
+use spdx;
+use yaml_rust::Yaml;
+
+use packages::{exported_packages, read_locked_packages};
+use requirements::read_pip_entries;
+use spec::normalize_pip_name;
+use Result;
+
+// RFC 4122's URL namespace, for name-based UUIDs.
+const UUID_NAMESPACE: [u8; 16] = [
+    0x6b, 0xa7, 0xb8, 0x11, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8,
+];
+
+/// A locked package, as an SBOM describes it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Component {
+    pub name: String,
+    pub version: String,
+    pub purl: String,
+    /// Where the package was downloaded from, if the lockfile records it.
+    pub url: Option<String>,
+    pub license: Option<String>,
+    pub md5: Option<String>,
+    pub sha256: Option<String>,
+    pub pip: bool,
+}
+
+/// The conda and pip packages of a lockfile.  `base_dir` is the lockfile's directory, for any
+/// requirements files it references.
+pub fn components(lockfile: &Yaml, base_dir: &Path) -> Result<Vec<Component>> {
+    let mut conda = read_locked_packages(lockfile);
+    if conda.is_empty() {
+        conda = exported_packages(lockfile)?;
+    }
+    let mut components = Vec::new();
+    for record in conda {
+        let mut qualifiers = vec![("build", record.build.as_str())];
+        let channel = record.channel.as_deref().unwrap_or("");
+        // purl namespaces are path segments, so private channels go in a qualifier instead.
+        let namespace = if channel.contains("://") {
+            qualifiers.push(("repository_url", channel));
+            ""
+        } else {
+            channel
+        };
+        if let Some(ref subdir) = record.subdir {
+            qualifiers.push(("subdir", subdir));
+        }
+        components.push(Component {
+            purl: purl(
+                "conda",
+                namespace,
+                &record.name,
+                &record.version,
+                &qualifiers,
+            ),
+            name: record.name.clone(),
+            version: record.version.clone(),
+            url: record.url.clone(),
+            license: record.license.clone(),
+            md5: record.md5.clone(),
+            sha256: record.sha256.clone(),
+            pip: false,
+        });
+    }
+    for req in read_pip_entries(lockfile, base_dir)?.requirements() {
+        let version = match req.exact_version() {
+            Some(version) => version,
+            None => {
+                warn!(
+                    "{} is not pinned to a version; leaving it out of the SBOM",
+                    req
+                );
+                continue;
+            }
+        };
+        let sha256 = req
+            .hashes
+            .iter()
+            .find_map(|hash| hash.strip_prefix("sha256:"));
+        components.push(Component {
+            purl: purl("pypi", "", &normalize_pip_name(&req.name), version, &[]),
+            name: req.name.clone(),
+            version: version.to_string(),
+            url: req.url.clone(),
+            sha256: sha256.map(|sha256| sha256.to_string()),
+            pip: true,
+            ..Component::default()
+        });
+    }
+    Ok(components)
+}
+
+// `pkg:type/namespace/name@version?key=value&...`, with qualifiers sorted by key.
+fn purl(
+    kind: &str,
+    namespace: &str,
+    name: &str,
+    version: &str,
+    qualifiers: &[(&str, &str)],
+) -> String {
+    let mut purl = format!("pkg:{}/", kind);
+    for segment in namespace.split('/').filter(|segment| !segment.is_empty()) {
+        purl.push_str(&percent_encode(segment));
+        purl.push('/');
+    }
+    purl.push_str(&percent_encode(name));
+    purl.push('@');
+    purl.push_str(&percent_encode(version));
+    let mut qualifiers: Vec<&(&str, &str)> = qualifiers
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .collect();
+    qualifiers.sort();
+    for (idx, (key, value)) in qualifiers.iter().enumerate() {
+        purl.push(if idx == 0 { '?' } else { '&' });
+        purl.push_str(&format!("{}={}", key, percent_encode(value)));
+    }
+    purl
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// A name-based (version 5) UUID, so the same lockfile always gets the same serial number.
+fn uuid(name: &[u8]) -> String {
+    let mut m = sha1::Sha1::new();
+    m.update(&UUID_NAMESPACE);
+    m.update(name);
+    let mut bytes = m.digest().bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// Recipes record licenses however they like (`BSD`, `PSF`, `MIT/X11`); only pass on valid SPDX
+// expressions, built from SPDX License List identifiers and `LicenseRef-*`.
+fn spdx_expression(license: &str) -> Option<&str> {
+    match spdx::Expression::parse(license) {
+        Ok(_) => Some(license),
+        Err(_) => None,
+    }
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    let mut object = Map::new();
+    for (key, value) in fields {
+        object.insert(key.to_string(), value);
+    }
+    Value::Object(object)
+}
+
+/// A CycloneDX 1.5 JSON document.
+pub fn cyclonedx(
+    env_name: &str,
+    components: &[Component],
+    lockfile_data: &str,
+    timestamp: &str,
+    tool_version: &str,
+) -> Value {
+    let mut entries = Vec::new();
+    for component in components {
+        let mut fields = vec![
+            ("type", string("library")),
+            ("bom-ref", string(&component.purl)),
+            ("name", string(&component.name)),
+            ("version", string(&component.version)),
+            ("purl", string(&component.purl)),
+        ];
+        let mut hashes = Vec::new();
+        if let Some(ref md5) = component.md5 {
+            hashes.push(object(vec![
+                ("alg", string("MD5")),
+                ("content", string(md5)),
+            ]));
+        }
+        if let Some(ref sha256) = component.sha256 {
+            hashes.push(object(vec![
+                ("alg", string("SHA-256")),
+                ("content", string(sha256)),
+            ]));
+        }
+        if !hashes.is_empty() {
+            fields.push(("hashes", Value::Array(hashes)));
+        }
+        if let Some(ref license) = component.license {
+            let license = match spdx_expression(license) {
+                Some(expression) => object(vec![("expression", string(expression))]),
+                None => object(vec![("license", object(vec![("name", string(license))]))]),
+            };
+            fields.push(("licenses", Value::Array(vec![license])));
+        }
+        if let Some(ref url) = component.url {
+            let reference = object(vec![("type", string("distribution")), ("url", string(url))]);
+            fields.push(("externalReferences", Value::Array(vec![reference])));
+        }
+        entries.push(object(fields));
+    }
+
+    let tool = object(vec![
+        ("type", string("application")),
+        ("name", string("conda-lockfile")),
+        ("version", string(tool_version)),
+    ]);
+    let metadata = object(vec![
+        ("timestamp", string(timestamp)),
+        (
+            "tools",
+            object(vec![("components", Value::Array(vec![tool]))]),
+        ),
+        (
+            "component",
+            object(vec![
+                ("type", string("application")),
+                ("name", string(env_name)),
+            ]),
+        ),
+    ]);
+    object(vec![
+        ("bomFormat", string("CycloneDX")),
+        ("specVersion", string("1.5")),
+        (
+            "serialNumber",
+            string(&format!("urn:uuid:{}", uuid(lockfile_data.as_bytes()))),
+        ),
+        ("version", Value::from(1)),
+        ("metadata", metadata),
+        ("components", Value::Array(entries)),
+    ])
+}
+
+// SPDX identifiers may only contain letters, digits, `.` and `-`.
+fn spdx_id(component: &Component) -> String {
+    let kind = if component.pip { "pypi" } else { "conda" };
+    let id = format!("SPDXRef-{}-{}-{}", kind, component.name, component.version);
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// An SPDX 2.3 JSON document.
+pub fn spdx(
+    env_name: &str,
+    components: &[Component],
+    lockfile_data: &str,
+    timestamp: &str,
+    tool_version: &str,
+) -> Value {
+    let mut packages = Vec::new();
+    let mut relationships = Vec::new();
+    for component in components {
+        let id = spdx_id(component);
+        let license = component
+            .license
+            .as_deref()
+            .and_then(spdx_expression)
+            .unwrap_or("NOASSERTION");
+        let mut checksums = Vec::new();
+        if let Some(ref md5) = component.md5 {
+            checksums.push(object(vec![
+                ("algorithm", string("MD5")),
+                ("checksumValue", string(md5)),
+            ]));
+        }
+        if let Some(ref sha256) = component.sha256 {
+            checksums.push(object(vec![
+                ("algorithm", string("SHA256")),
+                ("checksumValue", string(sha256)),
+            ]));
+        }
+        let purl = object(vec![
+            ("referenceCategory", string("PACKAGE-MANAGER")),
+            ("referenceType", string("purl")),
+            ("referenceLocator", string(&component.purl)),
+        ]);
+        let mut fields = vec![
+            ("SPDXID", string(&id)),
+            ("name", string(&component.name)),
+            ("versionInfo", string(&component.version)),
+            (
+                "downloadLocation",
+                string(component.url.as_deref().unwrap_or("NOASSERTION")),
+            ),
+            ("filesAnalyzed", Value::Bool(false)),
+            ("licenseConcluded", string("NOASSERTION")),
+            ("licenseDeclared", string(license)),
+            ("copyrightText", string("NOASSERTION")),
+            ("externalRefs", Value::Array(vec![purl])),
+        ];
+        if !checksums.is_empty() {
+            fields.push(("checksums", Value::Array(checksums)));
+        }
+        packages.push(object(fields));
+        relationships.push(object(vec![
+            ("spdxElementId", string("SPDXRef-DOCUMENT")),
+            ("relationshipType", string("DESCRIBES")),
+            ("relatedSpdxElement", string(&id)),
+        ]));
+    }
+
+    let namespace = format!(
+        "https://spdx.org/spdxdocs/{}-{}",
+        percent_encode(env_name),
+        uuid(lockfile_data.as_bytes())
+    );
+    let creation_info = object(vec![
+        ("created", string(timestamp)),
+        (
+            "creators",
+            Value::Array(vec![string(&format!(
+                "Tool: conda-lockfile-{}",
+                tool_version
+            ))]),
+        ),
+    ]);
+    object(vec![
+        ("spdxVersion", string("SPDX-2.3")),
+        ("dataLicense", string("CC0-1.0")),
+        ("SPDXID", string("SPDXRef-DOCUMENT")),
+        ("name", string(env_name)),
+        ("documentNamespace", string(&namespace)),
+        ("creationInfo", creation_info),
+        ("packages", Value::Array(packages)),
+        ("relationships", Value::Array(relationships)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use yaml_rust::YamlLoader;
+
+    const LOCKFILE: &str = "name: app
+channels:
+  - conda-forge
+dependencies:
+  - numpy=1.26.0=py311h64a7726_0
+  - internal-lib=1.0.0=py_0
+  - pip:
+    - PyYAML==6.0.1
+    - Requests @ https://files.example.com/requests-2.31.0-py3-none-any.whl --hash=sha256:abc
+packages:
+  numpy:
+    version: 1.26.0
+    build: py311h64a7726_0
+    channel: conda-forge
+    subdir: linux-64
+    url: https://conda.anaconda.org/conda-forge/linux-64/numpy-1.26.0-py311h64a7726_0.conda
+    md5: 'ab12'
+    sha256: 'cd34'
+    license: BSD-3-Clause
+  internal-lib:
+    version: 1.0.0
+    build: py_0
+    channel: https://conda.internal/private
+    subdir: noarch
+    license: BSD
+";
+
+    fn lockfile_components() -> Vec<Component> {
+        let lockfile = &YamlLoader::load_from_str(LOCKFILE).unwrap()[0];
+        components(lockfile, Path::new("")).unwrap()
+    }
+
+    #[test]
+    fn package_urls() {
+        let purls: Vec<String> = lockfile_components()
+            .into_iter()
+            .map(|component| component.purl)
+            .collect();
+        assert_eq!(
+            purls,
+            [
+                "pkg:conda/conda-forge/numpy@1.26.0?build=py311h64a7726_0&subdir=linux-64",
+                "pkg:conda/internal-lib@1.0.0?build=py_0&repository_url=https%3A%2F%2Fconda.internal%2Fprivate&subdir=noarch",
+                "pkg:pypi/pyyaml@6.0.1",
+                "pkg:pypi/requests@2.31.0",
+            ]
+        );
+        assert_eq!(
+            purl(
+                "conda",
+                "pkgs/main",
+                "python",
+                "3.11.5",
+                &[("build", "h955ad1f_0")]
+            ),
+            "pkg:conda/pkgs/main/python@3.11.5?build=h955ad1f_0"
+        );
+    }
+
+    #[test]
+    fn cyclonedx_document() {
+        let components = lockfile_components();
+        let bom = cyclonedx(
+            "app",
+            &components,
+            LOCKFILE,
+            "2024-01-31T12:00:00Z",
+            "0.7.0",
+        );
+        // The fields and formats the CycloneDX 1.5 JSON schema requires.
+        assert_eq!(bom["bomFormat"], "CycloneDX");
+        assert_eq!(bom["specVersion"], "1.5");
+        assert_eq!(bom["version"], 1);
+        let serial = bom["serialNumber"].as_str().unwrap();
+        let groups: Vec<usize> = serial
+            .strip_prefix("urn:uuid:")
+            .unwrap()
+            .split('-')
+            .map(|group| {
+                assert!(group
+                    .chars()
+                    .all(|c| c.is_ascii_hexdigit() && !c.is_uppercase()));
+                group.len()
+            })
+            .collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert_eq!(bom["metadata"]["timestamp"], "2024-01-31T12:00:00Z");
+        assert_eq!(
+            bom["metadata"]["tools"]["components"][0]["name"],
+            "conda-lockfile"
+        );
+        assert_eq!(
+            bom["metadata"]["tools"]["components"][0]["version"],
+            "0.7.0"
+        );
+        assert_eq!(bom["metadata"]["component"]["type"], "application");
+        assert_eq!(bom["metadata"]["component"]["name"], "app");
+        let entries = bom["components"].as_array().unwrap();
+        assert_eq!(entries.len(), components.len());
+        let mut refs = Vec::new();
+        for entry in entries {
+            assert_eq!(entry["type"], "library");
+            assert!(entry["name"].is_string());
+            refs.push(entry["bom-ref"].as_str().unwrap());
+        }
+        refs.sort();
+        refs.dedup();
+        assert_eq!(refs.len(), entries.len());
+
+        // The serial number identifies the lockfile it was made from.
+        let other = cyclonedx(
+            "app",
+            &components,
+            "name: other\n",
+            "2024-01-31T12:00:00Z",
+            "0.7.0",
+        );
+        assert_ne!(other["serialNumber"], bom["serialNumber"]);
+
+        let numpy = &bom["components"][0];
+        assert_eq!(numpy["hashes"][1]["alg"], "SHA-256");
+        assert_eq!(numpy["hashes"][1]["content"], "cd34");
+        assert_eq!(numpy["licenses"][0]["expression"], "BSD-3-Clause");
+        assert_eq!(numpy["externalReferences"][0]["type"], "distribution");
+        let internal = &bom["components"][1];
+        // Not an SPDX License List identifier, so not an expression.
+        assert_eq!(internal["licenses"][0]["license"]["name"], "BSD");
+        assert_eq!(internal["hashes"], Value::Null);
+        assert_eq!(bom["components"][3]["hashes"][0]["content"], "abc");
+    }
+
+    #[test]
+    fn spdx_document() {
+        let components = lockfile_components();
+        let doc = spdx(
+            "app",
+            &components,
+            LOCKFILE,
+            "2024-01-31T12:00:00Z",
+            "0.7.0",
+        );
+        assert_eq!(doc["spdxVersion"], "SPDX-2.3");
+        assert_eq!(doc["packages"].as_array().unwrap().len(), 4);
+        let numpy = &doc["packages"][0];
+        assert_eq!(numpy["SPDXID"], "SPDXRef-conda-numpy-1.26.0");
+        assert_eq!(numpy["licenseDeclared"], "BSD-3-Clause");
+        assert_eq!(numpy["checksums"][0]["algorithm"], "MD5");
+        assert_eq!(
+            numpy["externalRefs"][0]["referenceLocator"],
+            "pkg:conda/conda-forge/numpy@1.26.0?build=py311h64a7726_0&subdir=linux-64"
+        );
+        let internal = &doc["packages"][1];
+        assert_eq!(internal["licenseDeclared"], "NOASSERTION");
+        assert_eq!(internal["downloadLocation"], "NOASSERTION");
+        assert_eq!(doc["packages"][2]["SPDXID"], "SPDXRef-pypi-PyYAML-6.0.1");
+        assert_eq!(
+            doc["relationships"][3]["relatedSpdxElement"],
+            "SPDXRef-pypi-Requests-2.31.0"
+        );
+    }
+
+    #[test]
+    fn spdx_expressions() {
+        for license in &[
+            "MIT",
+            "Apache-2.0 OR MIT",
+            "(MIT OR BSD-3-Clause) AND Zlib",
+            "GPL-2.0-or-later WITH Classpath-exception-2.0",
+            "LicenseRef-Nextdoor-Internal",
+        ] {
+            assert_eq!(spdx_expression(license), Some(*license));
+        }
+        for license in &[
+            "",
+            "BSD 3-Clause",
+            "Proprietary license",
+            "MIT OR",
+            "MIT/X11",
+            "BSD",
+            "PSF",
+            "GPL",
+            "LGPL",
+            "BSD OR MIT",
+        ] {
+            assert_eq!(spdx_expression(license), None, "{}", license);
+        }
+    }
+
+    #[test]
+    fn name_based_uuids() {
+        // As Python's `uuid.uuid5(uuid.NAMESPACE_URL, "https://example.com")`.
+        assert_eq!(
+            uuid(b"https://example.com"),
+            "4fd35a71-71ef-5a55-a9d9-aa75c889a6d0"
+        );
+    }
+}
@@ -0,0 +1,561 @@
+use std::fmt;
+use std::io::Error as ioError;
+
+use version::{conda_version_matches, glob_matches, pip_version_matches};
+use Result;
+
+// Conda platform subdirectories that may appear as the last component of a channel, e.g.
+// `conda-forge/linux-64::numpy`.
+const SUBDIRS: &[&str] = &[
+    "linux-32",
+    "linux-64",
+    "linux-aarch64",
+    "linux-armv6l",
+    "linux-armv7l",
+    "linux-ppc64le",
+    "linux-s390x",
+    "noarch",
+    "osx-64",
+    "osx-arm64",
+    "win-32",
+    "win-64",
+    "win-arm64",
+];
+
+/// A conda package specification, as found in the `dependencies` section of a depfile or
+/// lockfile.
+///
+/// Handles the forms conda accepts: `numpy`, `numpy>=1.20`, `python 3.8.*`,
+/// `numpy=1.26.0=py311h64a7726_0`, `conda-forge::pandas` and `pkg[build=py38*]`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MatchSpec {
+    pub channel: Option<String>,
+    pub subdir: Option<String>,
+    pub name: String,
+    pub version: Option<String>,
+    pub build: Option<String>,
+}
+
+impl MatchSpec {
+    pub fn parse(spec: &str) -> Result<MatchSpec> {
+        let mut spec_str = spec.split('#').next().unwrap_or("").trim();
+        let mut match_spec = MatchSpec::default();
+
+        // Bracketed key/values trail everything else.
+        let mut bracket_version = None;
+        let mut bracket_build = None;
+        if spec_str.ends_with(']') {
+            let open = match spec_str.find('[') {
+                Some(idx) => idx,
+                None => return Err(invalid_spec(spec, "unbalanced brackets")),
+            };
+            for pair in split_unquoted(&spec_str[open + 1..spec_str.len() - 1], ',') {
+                let mut kv = pair.splitn(2, '=');
+                let key = kv.next().unwrap_or("").trim();
+                let value = match kv.next() {
+                    Some(value) => unquote(value.trim()).to_string(),
+                    None => return Err(invalid_spec(spec, "bracket entries must be key=value")),
+                };
+                match key {
+                    "version" => bracket_version = Some(value),
+                    "build" => bracket_build = Some(value),
+                    "channel" => match_spec.channel = Some(value),
+                    "subdir" => match_spec.subdir = Some(value),
+                    _ => debug!("Ignoring {}={} in {}", key, value, spec),
+                }
+            }
+            spec_str = spec_str[..open].trim();
+        }
+
+        // `channel::name` or `channel/subdir::name`
+        if let Some(idx) = spec_str.rfind("::") {
+            let mut channel = &spec_str[..idx];
+            if let Some(slash) = channel.rfind('/') {
+                if SUBDIRS.contains(&&channel[slash + 1..]) {
+                    match_spec.subdir = Some(channel[slash + 1..].to_string());
+                    channel = &channel[..slash];
+                }
+            }
+            match_spec.channel = Some(channel.to_string());
+            spec_str = &spec_str[idx + 2..];
+        }
+
+        let name_end = spec_str
+            .find(|c: char| c.is_whitespace() || "=<>!~".contains(c))
+            .unwrap_or(spec_str.len());
+        match_spec.name = spec_str[..name_end].to_lowercase();
+        if match_spec.name.is_empty() {
+            return Err(invalid_spec(spec, "missing package name"));
+        }
+
+        let (version, build) = split_version_build(&spec_str[name_end..]);
+        match_spec.version = bracket_version.or(version);
+        match_spec.build = bracket_build.or(build);
+        Ok(match_spec)
+    }
+
+    /// The version this spec pins exactly, as written by `conda env export`.
+    pub fn exact_version(&self) -> Option<&str> {
+        let version = self.version.as_ref()?;
+        let version = version.trim_start_matches("==");
+        if version.contains(|c: char| "*<>!~=,|".contains(c)) {
+            None
+        } else {
+            Some(version)
+        }
+    }
+
+    /// The version this spec pins when it comes from a lockfile.  Unlike in a depfile, a
+    /// hand-written `numpy=1.26.0` there means exactly 1.26.0 rather than `1.26.0.*`.
+    pub fn locked_version(&self) -> Option<&str> {
+        if let Some(version) = self.exact_version() {
+            return Some(version);
+        }
+        let version = self.version.as_ref()?.trim_end_matches(".*");
+        if version.is_empty() || version.contains(|c: char| "*<>!~=,|".contains(c)) {
+            None
+        } else {
+            Some(version)
+        }
+    }
+
+    /// Whether the exact package pinned by `locked` satisfies this spec.
+    pub fn is_satisfied_by(&self, locked: &MatchSpec) -> Result<bool> {
+        if self.name != locked.name {
+            return Ok(false);
+        }
+        if let Some(ref constraint) = self.version {
+            let version = match locked.locked_version() {
+                Some(version) => version,
+                None => return Err(not_pinned(locked)),
+            };
+            if !conda_version_matches(constraint, version)? {
+                return Ok(false);
+            }
+        }
+        if let Some(ref constraint) = self.build {
+            match locked.build {
+                Some(ref build) if glob_matches(constraint, build) => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl fmt::Display for MatchSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref channel) = self.channel {
+            write!(f, "{}", channel)?;
+            if let Some(ref subdir) = self.subdir {
+                write!(f, "/{}", subdir)?;
+            }
+            write!(f, "::")?;
+        }
+        write!(f, "{}", self.name)?;
+        if let Some(ref version) = self.version {
+            write!(f, " {}", version)?;
+        }
+        if let Some(ref build) = self.build {
+            if self.version.is_none() {
+                write!(f, " *")?;
+            }
+            write!(f, " {}", build)?;
+        }
+        Ok(())
+    }
+}
+
+// Split the part of a spec following the package name into a version constraint and a build
+// constraint.  Mirrors conda's handling of the `name=version=build` and `name version build`
+// forms, including turning `name=1.2` into the fuzzy `1.2.*`.
+fn split_version_build(rest: &str) -> (Option<String>, Option<String>) {
+    // Drop whitespace around operators so `numpy >= 1.20, < 2` reads as `>=1.20,<2`.
+    let mut collapsed = String::new();
+    for c in rest.trim().chars() {
+        if c.is_whitespace() {
+            if !collapsed.ends_with(|p: char| "=<>!~,|".contains(p)) {
+                collapsed.push(' ');
+            }
+        } else {
+            if ",|".contains(c) || (c != '=' && "<>!~".contains(c)) {
+                while collapsed.ends_with(' ') {
+                    collapsed.pop();
+                }
+            }
+            collapsed.push(c);
+        }
+    }
+    if collapsed.is_empty() {
+        return (None, None);
+    }
+
+    let mut parts = collapsed.split_whitespace();
+    if collapsed.contains(' ') {
+        let version = parts.next().map(|v| v.to_string());
+        let build = parts.next().map(|b| b.to_string());
+        return (version, build);
+    }
+
+    if collapsed.starts_with('=') && !collapsed.starts_with("==") {
+        let mut pieces = collapsed[1..].splitn(2, '=');
+        let version = pieces.next().unwrap_or("").to_string();
+        if let Some(build) = pieces.next() {
+            return (Some(version), Some(build.to_string()));
+        }
+        if version.contains(|c: char| "*<>!~=,|".contains(c)) {
+            return (Some(version), None);
+        }
+        return (Some(format!("{}.*", version)), None);
+    }
+    (Some(collapsed), None)
+}
+
+/// A pip requirement in PEP 508 form, e.g. `requests[security]>=2 ; python_version < "3.8"` or
+/// `pkg @ https://example.com/pkg-1.0.tar.gz`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PipRequirement {
+    pub name: String,
+    pub extras: Vec<String>,
+    pub specifier: Option<String>,
+    pub url: Option<String>,
+    pub marker: Option<String>,
+    /// `--hash` options, e.g. `sha256:<hex>`, used by pip's hash-checking mode.
+    pub hashes: Vec<String>,
+}
+
+impl PipRequirement {
+    pub fn parse(requirement: &str) -> Result<PipRequirement> {
+        let requirement = requirement.trim();
+        let name_end = requirement
+            .find(|c: char| !(c.is_alphanumeric() || "._-".contains(c)))
+            .unwrap_or(requirement.len());
+        let mut req = PipRequirement {
+            name: requirement[..name_end].to_string(),
+            ..PipRequirement::default()
+        };
+        if !req.name.starts_with(char::is_alphanumeric)
+            || !req.name.ends_with(char::is_alphanumeric)
+        {
+            return Err(invalid_spec(requirement, "missing package name"));
+        }
+
+        let mut rest = requirement[name_end..].trim_start();
+        if rest.starts_with('[') {
+            let close = match rest.find(']') {
+                Some(idx) => idx,
+                None => return Err(invalid_spec(requirement, "unbalanced brackets")),
+            };
+            req.extras = rest[1..close]
+                .split(',')
+                .map(|extra| extra.trim().to_string())
+                .filter(|extra| !extra.is_empty())
+                .collect();
+            rest = rest[close + 1..].trim_start();
+        }
+
+        if let Some(url_part) = rest.strip_prefix('@') {
+            // URLs may legitimately contain `;`, so PEP 508 requires whitespace before the marker.
+            let url_part = url_part.trim();
+            let (url, marker) = match url_part.find(" ;") {
+                Some(idx) => (&url_part[..idx], Some(&url_part[idx + 2..])),
+                None => (url_part, None),
+            };
+            if url.is_empty() {
+                return Err(invalid_spec(requirement, "missing url"));
+            }
+            req.url = Some(url.trim().to_string());
+            req.marker = marker.map(|m| m.trim().to_string());
+            return Ok(req);
+        }
+
+        let (specifier, marker) = match rest.find(';') {
+            Some(idx) => (&rest[..idx], Some(&rest[idx + 1..])),
+            None => (rest, None),
+        };
+        let specifier: String = specifier
+            .trim()
+            .trim_start_matches('(')
+            .trim_end_matches(')')
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        if !specifier.is_empty() {
+            if !specifier.starts_with(|c: char| "=<>!~".contains(c)) {
+                return Err(invalid_spec(requirement, "expected a version specifier"));
+            }
+            req.specifier = Some(specifier);
+        }
+        req.marker = marker.map(|m| m.trim().to_string());
+        Ok(req)
+    }
+
+    /// The version this requirement pins exactly, as written by `conda env export`.
+    pub fn exact_version(&self) -> Option<&str> {
+        let specifier = match self.specifier {
+            Some(ref specifier) => specifier,
+            None => return self.url_version(),
+        };
+        let version = specifier.trim_start_matches("===").trim_start_matches("==");
+        if version.len() == specifier.len() || version.contains(|c: char| "*<>!~=,".contains(c)) {
+            None
+        } else {
+            Some(version)
+        }
+    }
+
+    // Hashed lockfiles pin packages by artifact URL, whose filename carries the version:
+    // `name-1.0-py3-none-any.whl` or `name-1.0.tar.gz`.
+    fn url_version(&self) -> Option<&str> {
+        let url = self.url.as_ref()?;
+        let filename = url.split(['#', '?']).next()?.rsplit('/').next()?;
+        if let Some(wheel) = filename.strip_suffix(".whl") {
+            return wheel.split('-').nth(1);
+        }
+        let stem = [".tar.gz", ".tar.bz2", ".zip", ".tgz"]
+            .iter()
+            .find_map(|ext| filename.strip_suffix(ext))?;
+        let name = self.normalized_name();
+        stem.match_indices('-')
+            .map(|(idx, _)| idx)
+            .find(|&idx| normalize_pip_name(&stem[..idx]) == name)
+            .map(|idx| &stem[idx + 1..])
+    }
+
+    /// Whether the exact package pinned by `locked` satisfies this requirement.
+    pub fn is_satisfied_by(&self, locked: &PipRequirement) -> Result<bool> {
+        if self.normalized_name() != locked.normalized_name() {
+            return Ok(false);
+        }
+        match self.specifier {
+            Some(ref specifier) => match locked.exact_version() {
+                Some(version) => pip_version_matches(specifier, version),
+                None => Err(not_pinned(locked)),
+            },
+            None => Ok(true),
+        }
+    }
+
+    /// The name normalized per PEP 503, so `Foo_Bar` and `foo-bar` compare equal.
+    pub fn normalized_name(&self) -> String {
+        normalize_pip_name(&self.name)
+    }
+}
+
+impl fmt::Display for PipRequirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.extras.is_empty() {
+            write!(f, "[{}]", self.extras.join(","))?;
+        }
+        if let Some(ref url) = self.url {
+            write!(f, " @ {}", url)?;
+        }
+        if let Some(ref specifier) = self.specifier {
+            write!(f, "{}", specifier)?;
+        }
+        if let Some(ref marker) = self.marker {
+            write!(f, " ; {}", marker)?;
+        }
+        for hash in self.hashes.iter() {
+            write!(f, " --hash={}", hash)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn normalize_pip_name(name: &str) -> String {
+    let mut normalized = String::new();
+    for c in name.chars() {
+        if "-_.".contains(c) {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.extend(c.to_lowercase());
+        }
+    }
+    normalized
+}
+
+// Split on `sep` where it isn't inside quotes, as in `pkg[version='>=1.20,<2', build=py38*]`.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == sep => {
+                parts.push(&s[start..idx]);
+                start = idx + c.len_utf8();
+            }
+            None => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim_matches(|c| c == '"' || c == '\'')
+}
+
+fn not_pinned<T: fmt::Display>(locked: &T) -> Box<dyn std::error::Error> {
+    ioError::other(format!(
+        "Locked package {} is not pinned to an exact version",
+        locked
+    ))
+    .into()
+}
+
+fn invalid_spec(spec: &str, reason: &str) -> Box<dyn std::error::Error> {
+    ioError::other(format!("Invalid spec {:?}: {}", spec, reason)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conda(spec: &str) -> MatchSpec {
+        MatchSpec::parse(spec).unwrap()
+    }
+
+    #[test]
+    fn matchspec_name_only() {
+        let spec = conda("NumPy");
+        assert_eq!(spec.name, "numpy");
+        assert_eq!(spec.version, None);
+        assert_eq!(spec.build, None);
+    }
+
+    #[test]
+    fn matchspec_operators() {
+        assert_eq!(conda("numpy>=1.20").version.unwrap(), ">=1.20");
+        assert_eq!(conda("numpy >= 1.20, <2").version.unwrap(), ">=1.20,<2");
+        assert_eq!(conda("numpy==1.20").version.unwrap(), "==1.20");
+        assert_eq!(conda("numpy=1.20").version.unwrap(), "1.20.*");
+        assert_eq!(conda("numpy=1.20|1.21").version.unwrap(), "1.20|1.21");
+    }
+
+    #[test]
+    fn matchspec_version_and_build() {
+        let spec = conda("python 3.8.*");
+        assert_eq!(spec.name, "python");
+        assert_eq!(spec.version.unwrap(), "3.8.*");
+
+        let spec = conda("numpy=1.26.0=py311h64a7726_0");
+        assert_eq!(spec.version.unwrap(), "1.26.0");
+        assert_eq!(spec.build.unwrap(), "py311h64a7726_0");
+
+        let spec = conda("numpy 1.26.0 py311*");
+        assert_eq!(spec.version.unwrap(), "1.26.0");
+        assert_eq!(spec.build.unwrap(), "py311*");
+    }
+
+    #[test]
+    fn matchspec_channel_and_brackets() {
+        let spec = conda("conda-forge::pandas");
+        assert_eq!(spec.channel.unwrap(), "conda-forge");
+        assert_eq!(spec.name, "pandas");
+
+        let spec = conda("conda-forge/linux-64::pandas>=2");
+        assert_eq!(spec.channel.unwrap(), "conda-forge");
+        assert_eq!(spec.subdir.unwrap(), "linux-64");
+        assert_eq!(spec.version.unwrap(), ">=2");
+
+        let spec = conda("pkg[build=py38*, version='>=1.0']");
+        assert_eq!(spec.name, "pkg");
+        assert_eq!(spec.build.unwrap(), "py38*");
+        assert_eq!(spec.version.unwrap(), ">=1.0");
+
+        let spec = conda("numpy[version='>=1.20,<2']");
+        assert_eq!(spec.name, "numpy");
+        assert_eq!(spec.version.unwrap(), ">=1.20,<2");
+
+        let spec = conda("numpy[version=\">=1.20,<2\", build=py311*]");
+        assert_eq!(spec.version.unwrap(), ">=1.20,<2");
+        assert_eq!(spec.build.unwrap(), "py311*");
+    }
+
+    #[test]
+    fn matchspec_invalid() {
+        assert!(MatchSpec::parse(">=1.0").is_err());
+        assert!(MatchSpec::parse("pkg[build]").is_err());
+    }
+
+    #[test]
+    fn matchspec_satisfaction() {
+        let locked = conda("numpy=1.26.0=py311h64a7726_0");
+        assert!(conda("numpy").is_satisfied_by(&locked).unwrap());
+        assert!(conda("numpy>=1.20,<2").is_satisfied_by(&locked).unwrap());
+        assert!(conda("numpy=1.26").is_satisfied_by(&locked).unwrap());
+        assert!(conda("numpy 1.26.* py311*")
+            .is_satisfied_by(&locked)
+            .unwrap());
+        assert!(!conda("numpy 1.26.* py38*")
+            .is_satisfied_by(&locked)
+            .unwrap());
+        assert!(!conda("numpy<1.26").is_satisfied_by(&locked).unwrap());
+        assert!(!conda("scipy").is_satisfied_by(&locked).unwrap());
+        assert!(conda("numpy>=1")
+            .is_satisfied_by(&conda("numpy>=1.20"))
+            .is_err());
+    }
+
+    #[test]
+    fn matchspec_satisfaction_without_build() {
+        let locked = conda("numpy=1.26.0");
+        assert_eq!(locked.version.as_deref(), Some("1.26.0.*"));
+        assert_eq!(locked.locked_version(), Some("1.26.0"));
+        assert!(conda("numpy>=1.20,<2").is_satisfied_by(&locked).unwrap());
+        assert!(conda("numpy==1.26.0").is_satisfied_by(&locked).unwrap());
+        assert!(!conda("numpy>1.26.0").is_satisfied_by(&locked).unwrap());
+    }
+
+    #[test]
+    fn pip_requirement_satisfaction() {
+        let locked = PipRequirement::parse("Requests==2.31.0").unwrap();
+        let requested = PipRequirement::parse("requests[security]>=2,<3").unwrap();
+        assert!(requested.is_satisfied_by(&locked).unwrap());
+        let requested = PipRequirement::parse("requests<2.31").unwrap();
+        assert!(!requested.is_satisfied_by(&locked).unwrap());
+
+        let url = "https://files.example.com/requests-2.31.0-py3-none-any.whl";
+        let locked = PipRequirement::parse(&format!("requests @ {}", url)).unwrap();
+        assert_eq!(locked.exact_version().unwrap(), "2.31.0");
+        let url = "https://files.example.com/zope.interface-6.0.tar.gz#sha256=abc";
+        let locked = PipRequirement::parse(&format!("zope-interface @ {}", url)).unwrap();
+        assert_eq!(locked.exact_version().unwrap(), "6.0");
+    }
+
+    #[test]
+    fn pip_requirement_specifiers() {
+        let req = PipRequirement::parse("requests[security, socks]>=2").unwrap();
+        assert_eq!(req.name, "requests");
+        assert_eq!(req.extras, ["security", "socks"]);
+        assert_eq!(req.specifier.unwrap(), ">=2");
+
+        let req =
+            PipRequirement::parse("Django (>= 3.2, < 4) ; python_version >= \"3.6\"").unwrap();
+        assert_eq!(req.normalized_name(), "django");
+        assert_eq!(req.specifier.unwrap(), ">=3.2,<4");
+        assert_eq!(req.marker.unwrap(), "python_version >= \"3.6\"");
+    }
+
+    #[test]
+    fn pip_requirement_url() {
+        let req = PipRequirement::parse("my_pkg @ https://example.com/my_pkg-1.0.tar.gz").unwrap();
+        assert_eq!(req.normalized_name(), "my-pkg");
+        assert_eq!(req.url.unwrap(), "https://example.com/my_pkg-1.0.tar.gz");
+        assert_eq!(req.specifier, None);
+    }
+
+    #[test]
+    fn pip_requirement_invalid() {
+        assert!(PipRequirement::parse("-r requirements.txt").is_err());
+        assert!(PipRequirement::parse("requests 2.0").is_err());
+    }
+}
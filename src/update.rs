@@ -0,0 +1,162 @@
+use std::path::Path;
+
+use yaml_rust::Yaml;
+
+use credentials;
+use spec::{MatchSpec, PipRequirement};
+use {get_deps, pip, run_command_with_env, Result};
+
+/// The changes needed to bring an env built from one lockfile in line with another.
+#[derive(Debug, Default, PartialEq)]
+pub struct EnvDelta {
+    pub conda_remove: Vec<String>,
+    pub conda_install: Vec<MatchSpec>,
+    pub pip_remove: Vec<String>,
+    pub pip_install: Vec<PipRequirement>,
+}
+
+impl EnvDelta {
+    /// Compare the lockfile an env was built from (`before`) with the one it should match
+    /// (`after`).  Packages whose spec changed are reinstalled; conda and pip replace the old
+    /// version themselves.
+    pub fn between(
+        before: &Yaml,
+        before_dir: &Path,
+        after: &Yaml,
+        after_dir: &Path,
+    ) -> Result<EnvDelta> {
+        let (old_conda, old_pip) = get_deps(before, before_dir)?;
+        let (new_conda, new_pip) = get_deps(after, after_dir)?;
+
+        let mut delta = EnvDelta::default();
+        for old in old_conda.iter() {
+            if !new_conda.iter().any(|new| new.name == old.name) {
+                delta.conda_remove.push(old.name.clone());
+            }
+        }
+        for new in new_conda {
+            if !old_conda.contains(&new) {
+                delta.conda_install.push(new);
+            }
+        }
+        for old in old_pip.iter() {
+            if !new_pip
+                .iter()
+                .any(|new| new.normalized_name() == old.normalized_name())
+            {
+                delta.pip_remove.push(old.name.clone());
+            }
+        }
+        for new in new_pip {
+            if !old_pip.contains(&new) {
+                delta.pip_install.push(new);
+            }
+        }
+        Ok(delta)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conda_remove.is_empty()
+            && self.conda_install.is_empty()
+            && self.pip_remove.is_empty()
+            && self.pip_install.is_empty()
+    }
+
+    /// Apply the delta to the env at `prefix`.  Only the listed packages are touched, so nothing
+    /// is re-solved: the new lockfile already describes a consistent env.  pip looks for packages
+    /// on the new lockfile's `pip_index_options`.
+    pub fn apply(
+        &self,
+        conda_path: &str,
+        prefix: &Path,
+        channels: &[&str],
+        pip_index_options: &[String],
+        conda_env: &[(&str, &str)],
+    ) -> Result<()> {
+        let prefix_str = prefix.to_str().unwrap();
+        if !self.pip_remove.is_empty() {
+            info!("Removing pip packages {:?}", self.pip_remove);
+            pip::uninstall(prefix, &self.pip_remove)?;
+        }
+        if !self.conda_remove.is_empty() {
+            info!("Removing conda packages {:?}", self.conda_remove);
+            let mut args = vec!["remove", "--yes", "--force", "--prefix", prefix_str];
+            args.extend(self.conda_remove.iter().map(|name| name.as_str()));
+            run_command_with_env(conda_path, &args, conda_env)?;
+        }
+        if !self.conda_install.is_empty() {
+            let specs: Vec<String> = self.conda_install.iter().map(|s| s.to_string()).collect();
+            let masked: Vec<String> = specs.iter().map(|spec| credentials::mask(spec)).collect();
+            info!("Installing conda packages {:?}", masked);
+            let mut args = vec!["install", "--yes", "--no-deps", "--prefix", prefix_str];
+            for channel in channels {
+                args.push("-c");
+                args.push(channel);
+            }
+            if !channels.is_empty() {
+                args.push("--override-channels");
+            }
+            args.extend(specs.iter().map(|spec| spec.as_str()));
+            run_command_with_env(conda_path, &args, conda_env)?;
+        }
+        if !self.pip_install.is_empty() {
+            info!("Installing {} pip packages", self.pip_install.len());
+            if self.pip_install.iter().any(|req| !req.hashes.is_empty()) {
+                pip::install_with_hashes(prefix, &self.pip_install, pip_index_options)?;
+            } else {
+                let specs: Vec<String> =
+                    self.pip_install.iter().map(|req| req.to_string()).collect();
+                pip::install(prefix, &specs, pip_index_options)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn delta_between_lockfiles() {
+        let before = &YamlLoader::load_from_str(
+            "name: app
+dependencies:
+  - python=3.11.5=hab00c5b_0
+  - numpy=1.26.0=py311h64a7726_0
+  - zlib=1.2.13=hd590300_5
+  - pip:
+    - requests==2.31.0
+    - idna==3.4
+",
+        )
+        .unwrap()[0];
+        let after = &YamlLoader::load_from_str(
+            "name: app
+dependencies:
+  - python=3.11.5=hab00c5b_0
+  - numpy=1.26.2=py311h64a7726_0
+  - xz=5.2.6=h166bdaf_0
+  - pip:
+    - requests==2.31.0
+    - Flask==3.0.0
+",
+        )
+        .unwrap()[0];
+
+        let delta = EnvDelta::between(before, Path::new(""), after, Path::new("")).unwrap();
+        assert_eq!(delta.conda_remove, ["zlib"]);
+        let installs: Vec<String> = delta.conda_install.iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            installs,
+            ["numpy 1.26.2 py311h64a7726_0", "xz 5.2.6 h166bdaf_0"]
+        );
+        assert_eq!(delta.pip_remove, ["idna"]);
+        assert_eq!(delta.pip_install[0].name, "Flask");
+
+        let unchanged = EnvDelta::between(after, Path::new(""), after, Path::new("")).unwrap();
+        assert!(unchanged.is_empty());
+    }
+}
@@ -0,0 +1,533 @@
+use std::cmp::Ordering;
+use std::io::Error as ioError;
+
+use Result;
+
+// A single piece of a conda version component.  The variant order is the sort order: strings
+// sort before numbers, and `post` sorts after everything.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Part {
+    Str(String),
+    Num(u64),
+    Inf,
+}
+
+/// A version ordered by conda's rules, e.g. `1.1dev1 < 1.1a1 < 1.1rc1 < 1.1 < 1.1.post1`.
+#[derive(Clone, Debug)]
+pub struct CondaVersion {
+    // The epoch is stored as the first component so prefix matching can treat it uniformly.
+    version: Vec<Vec<Part>>,
+    local: Vec<Vec<Part>>,
+}
+
+impl CondaVersion {
+    pub fn parse(version: &str) -> Result<CondaVersion> {
+        let normalized = version.trim().to_lowercase();
+        if normalized.is_empty()
+            || !normalized
+                .chars()
+                .all(|c| c.is_alphanumeric() || "._-+!".contains(c))
+        {
+            return Err(invalid_version(version));
+        }
+        let (epoch, rest) = match normalized.find('!') {
+            Some(idx) => (&normalized[..idx], &normalized[idx + 1..]),
+            None => ("0", normalized.as_str()),
+        };
+        let epoch: u64 = match epoch.parse() {
+            Ok(epoch) => epoch,
+            Err(_) => return Err(invalid_version(version)),
+        };
+        let (public, local) = match rest.find('+') {
+            Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+            None => (rest, ""),
+        };
+        if public.is_empty() {
+            return Err(invalid_version(version));
+        }
+
+        let mut components = vec![vec![Part::Num(epoch)]];
+        components.extend(split_components(public));
+        Ok(CondaVersion {
+            version: components,
+            local: split_components(local),
+        })
+    }
+
+    /// Whether this version starts with `prefix`, following conda's `1.2.*` semantics: `1.2.3`
+    /// starts with `1.2`, but `1.20` does not.
+    pub fn startswith(&self, prefix: &CondaVersion) -> bool {
+        let (mine, theirs) = if prefix.local.is_empty() {
+            (&self.version, &prefix.version)
+        } else {
+            if compare_components(&self.version, &prefix.version) != Ordering::Equal {
+                return false;
+            }
+            (&self.local, &prefix.local)
+        };
+        let last = theirs.len() - 1;
+        if mine.len() <= last
+            || compare_components(&mine[..last], &theirs[..last]) != Ordering::Equal
+        {
+            return false;
+        }
+        let (c1, c2) = (&mine[last], &theirs[last]);
+        let last_part = c2.len() - 1;
+        if c1.len() <= last_part || c1[..last_part] != c2[..last_part] {
+            return false;
+        }
+        match (&c1[last_part], &c2[last_part]) {
+            (Part::Str(s1), Part::Str(s2)) => s1.starts_with(s2.as_str()),
+            (p1, p2) => p1 == p2,
+        }
+    }
+}
+
+impl PartialEq for CondaVersion {
+    fn eq(&self, other: &CondaVersion) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CondaVersion {}
+
+impl PartialOrd for CondaVersion {
+    fn partial_cmp(&self, other: &CondaVersion) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CondaVersion {
+    fn cmp(&self, other: &CondaVersion) -> Ordering {
+        compare_components(&self.version, &other.version)
+            .then_with(|| compare_components(&self.local, &other.local))
+    }
+}
+
+fn split_components(version: &str) -> Vec<Vec<Part>> {
+    version
+        .split(['.', '_', '-'])
+        .filter(|component| !component.is_empty())
+        .map(|component| {
+            let mut parts = Vec::new();
+            let mut chars = component.chars().peekable();
+            while let Some(&c) = chars.peek() {
+                let is_digit = c.is_ascii_digit();
+                let mut run = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_digit() != is_digit {
+                        break;
+                    }
+                    run.push(c);
+                    chars.next();
+                }
+                parts.push(match run.as_str() {
+                    _ if is_digit => Part::Num(run.parse().unwrap_or(u64::MAX)),
+                    "post" => Part::Inf,
+                    // Upper case sorts before the lower case pre-release tags.
+                    "dev" => Part::Str("DEV".to_string()),
+                    _ => Part::Str(run),
+                });
+            }
+            // Components are always number-first so `1.1a1` compares against `1.1.0` sensibly.
+            if !matches!(parts.first(), Some(&Part::Num(_))) {
+                parts.insert(0, Part::Num(0));
+            }
+            parts
+        })
+        .collect()
+}
+
+fn compare_components(c1: &[Vec<Part>], c2: &[Vec<Part>]) -> Ordering {
+    let empty = Vec::new();
+    for i in 0..c1.len().max(c2.len()) {
+        let p1 = c1.get(i).unwrap_or(&empty);
+        let p2 = c2.get(i).unwrap_or(&empty);
+        for j in 0..p1.len().max(p2.len()) {
+            let part1 = p1.get(j).unwrap_or(&Part::Num(0));
+            let part2 = p2.get(j).unwrap_or(&Part::Num(0));
+            match part1.cmp(part2) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+    }
+    Ordering::Equal
+}
+
+/// Check a version against a conda version constraint such as `>=1.20,<2`, `3.8.*` or
+/// `1.20|1.21`.  `,` binds tighter than `|`.
+pub fn conda_version_matches(constraint: &str, version: &str) -> Result<bool> {
+    let version = CondaVersion::parse(version)?;
+    for alternative in constraint.split('|') {
+        let mut all_match = true;
+        for clause in alternative.split(',') {
+            if !conda_clause_matches(clause.trim(), &version)? {
+                all_match = false;
+                break;
+            }
+        }
+        if all_match {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn conda_clause_matches(clause: &str, version: &CondaVersion) -> Result<bool> {
+    if clause.is_empty() || clause == "*" {
+        return Ok(true);
+    }
+    let (operator, operand) = split_operator(clause);
+    let (operand, wildcard) = match operand.strip_suffix('*') {
+        Some(stripped) => (stripped.trim_end_matches('.'), true),
+        None => (operand, false),
+    };
+    let other = CondaVersion::parse(operand)?;
+    let matches = match operator {
+        "" | "==" | "=" if wildcard || operator == "=" => version.startswith(&other),
+        "" | "==" => *version == other,
+        "!=" if wildcard => !version.startswith(&other),
+        "!=" => *version != other,
+        ">=" => *version >= other,
+        "<=" => *version <= other,
+        ">" => *version > other,
+        "<" => *version < other,
+        "~=" => {
+            // `~=1.2.3` means `>=1.2.3` and starts with `1.2`.
+            let mut prefix = other.clone();
+            if prefix.version.len() > 2 {
+                prefix.version.pop();
+            }
+            *version >= other && version.startswith(&prefix)
+        }
+        _ => return Err(invalid_constraint(clause)),
+    };
+    Ok(matches)
+}
+
+// Epoch, release, pre, post and dev release, in PEP 440 sort order.
+type PipVersionKey = (u64, Vec<u64>, (u8, u8, u64), (u8, u64), (u8, u64));
+
+/// A PEP 440 version, e.g. `1!2.0.0rc1.post2.dev3+local.7`.
+#[derive(Clone, Debug)]
+pub struct PipVersion {
+    epoch: u64,
+    release: Vec<u64>,
+    pre: Option<(u8, u64)>,
+    post: Option<u64>,
+    dev: Option<u64>,
+    local: Vec<String>,
+}
+
+impl PipVersion {
+    pub fn parse(version: &str) -> Result<PipVersion> {
+        let normalized = version.trim().to_lowercase();
+        let mut rest = normalized.trim_start_matches('v');
+        let mut parsed = PipVersion {
+            epoch: 0,
+            release: Vec::new(),
+            pre: None,
+            post: None,
+            dev: None,
+            local: Vec::new(),
+        };
+
+        if let Some(idx) = rest.find('+') {
+            parsed.local = rest[idx + 1..]
+                .split(['.', '-', '_'])
+                .map(|segment| segment.to_string())
+                .collect();
+            rest = &rest[..idx];
+        }
+        if let Some(idx) = rest.find('!') {
+            parsed.epoch = match rest[..idx].parse() {
+                Ok(epoch) => epoch,
+                Err(_) => return Err(invalid_version(version)),
+            };
+            rest = &rest[idx + 1..];
+        }
+
+        let release_end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        for segment in rest[..release_end].trim_end_matches('.').split('.') {
+            match segment.parse() {
+                Ok(n) => parsed.release.push(n),
+                Err(_) => return Err(invalid_version(version)),
+            }
+        }
+        rest = &rest[release_end..];
+
+        while !rest.is_empty() {
+            let trimmed = rest.trim_start_matches(['.', '-', '_']);
+            let tag_end = trimmed
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(trimmed.len());
+            let tag = &trimmed[..tag_end];
+            let after_tag = trimmed[tag_end..].trim_start_matches(['.', '-', '_']);
+            let num_end = after_tag
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(after_tag.len());
+            let number = after_tag[..num_end].parse().unwrap_or(0);
+            match tag {
+                "a" | "alpha" if parsed.pre.is_none() => parsed.pre = Some((0, number)),
+                "b" | "beta" if parsed.pre.is_none() => parsed.pre = Some((1, number)),
+                "rc" | "c" | "pre" | "preview" if parsed.pre.is_none() => {
+                    parsed.pre = Some((2, number))
+                }
+                "post" | "rev" | "r" if parsed.post.is_none() => parsed.post = Some(number),
+                // An implicit post release, e.g. `1.0-1`.
+                "" if num_end > 0 && rest.starts_with('-') && parsed.post.is_none() => {
+                    parsed.post = Some(number)
+                }
+                "dev" if parsed.dev.is_none() => parsed.dev = Some(number),
+                _ => return Err(invalid_version(version)),
+            }
+            rest = &after_tag[num_end..];
+        }
+        Ok(parsed)
+    }
+
+    fn key(&self) -> PipVersionKey {
+        let mut release = self.release.clone();
+        while release.len() > 1 && release.last() == Some(&0) {
+            release.pop();
+        }
+        // A bare dev release (`1.0.dev1`) sorts before any pre-release of the same version.
+        let pre = match (self.pre, self.post, self.dev) {
+            (Some((kind, n)), _, _) => (1, kind, n),
+            (None, None, Some(_)) => (0, 0, 0),
+            _ => (2, 0, 0),
+        };
+        let post = self.post.map_or((0, 0), |n| (1, n));
+        let dev = self.dev.map_or((1, 0), |n| (0, n));
+        (self.epoch, release, pre, post, dev)
+    }
+
+    fn release_startswith(&self, prefix: &PipVersion) -> bool {
+        let mut release = self.release.clone();
+        release.resize(release.len().max(prefix.release.len()), 0);
+        self.epoch == prefix.epoch && release[..prefix.release.len()] == prefix.release[..]
+    }
+}
+
+impl PartialEq for PipVersion {
+    fn eq(&self, other: &PipVersion) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PipVersion {}
+
+impl PartialOrd for PipVersion {
+    fn partial_cmp(&self, other: &PipVersion) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PipVersion {
+    fn cmp(&self, other: &PipVersion) -> Ordering {
+        self.key()
+            .cmp(&other.key())
+            .then_with(|| self.local.cmp(&other.local))
+    }
+}
+
+/// Check a version against a PEP 440 specifier such as `>=2,<3`, `~=1.4.2` or `==1.2.*`.
+pub fn pip_version_matches(specifier: &str, version: &str) -> Result<bool> {
+    let parsed = PipVersion::parse(version)?;
+    for clause in specifier.split(',') {
+        let clause = clause.trim();
+        if clause.is_empty() {
+            continue;
+        }
+        let (operator, operand) = split_operator(clause);
+        if operator == "===" {
+            if version.trim() != operand {
+                return Ok(false);
+            }
+            continue;
+        }
+        let (operand, wildcard) = match operand.strip_suffix(".*") {
+            Some(stripped) => (stripped, true),
+            None => (operand, false),
+        };
+        let other = PipVersion::parse(operand)?;
+        // Local versions are ignored unless the specifier names one.
+        let mut candidate = parsed.clone();
+        if other.local.is_empty() {
+            candidate.local.clear();
+        }
+        let matches = match operator {
+            "==" if wildcard => candidate.release_startswith(&other),
+            "!=" if wildcard => !candidate.release_startswith(&other),
+            "==" => candidate == other,
+            "!=" => candidate != other,
+            ">=" => candidate >= other,
+            "<=" => candidate <= other,
+            ">" => candidate > other,
+            "<" => candidate < other,
+            "~=" if other.release.len() > 1 => {
+                let mut prefix = other.clone();
+                prefix.release.pop();
+                candidate >= other && candidate.release_startswith(&prefix)
+            }
+            _ => return Err(invalid_constraint(clause)),
+        };
+        if !matches {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Match a conda build string against a constraint that may contain `*` wildcards.
+pub fn glob_matches(pattern: &str, value: &str) -> bool {
+    let pieces: Vec<&str> = pattern.split('*').collect();
+    if pieces.len() == 1 {
+        return pattern == value;
+    }
+    let (first, last) = (pieces[0], pieces[pieces.len() - 1]);
+    if !value.starts_with(first) || value.len() < first.len() + last.len() {
+        return false;
+    }
+    let mut remaining = &value[first.len()..value.len() - last.len()];
+    if !value.ends_with(last) {
+        return false;
+    }
+    for piece in &pieces[1..pieces.len() - 1] {
+        match remaining.find(piece) {
+            Some(idx) => remaining = &remaining[idx + piece.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+fn split_operator(clause: &str) -> (&str, &str) {
+    let idx = clause
+        .find(|c: char| !"=<>!~".contains(c))
+        .unwrap_or(clause.len());
+    (&clause[..idx], clause[idx..].trim())
+}
+
+fn invalid_version(version: &str) -> Box<dyn std::error::Error> {
+    ioError::other(format!("Invalid version {:?}", version)).into()
+}
+
+fn invalid_constraint(constraint: &str) -> Box<dyn std::error::Error> {
+    ioError::other(format!("Invalid version constraint {:?}", constraint)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conda(version: &str) -> CondaVersion {
+        CondaVersion::parse(version).unwrap()
+    }
+
+    fn pip(version: &str) -> PipVersion {
+        PipVersion::parse(version).unwrap()
+    }
+
+    #[test]
+    fn conda_version_ordering() {
+        let ordered = [
+            "0.4",
+            "0.4.1.rc",
+            "0.4.1.RC",
+            "0.4.1",
+            "0.5a1",
+            "0.5b3",
+            "0.5C1",
+            "0.5",
+            "0.9.6",
+            "0.960923",
+            "1.0",
+            "1.1dev1",
+            "1.1a1",
+            "1.1.0rc1",
+            "1.1.0",
+            "1.1.0post1",
+            "1.1post1",
+            "1996.07.12",
+            "1!0.4.1",
+            "1!3.1.1.6",
+            "2!0.4.1",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(
+                conda(pair[0]) <= conda(pair[1]),
+                "{} <= {}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert_eq!(conda("1.1"), conda("1.1.0"));
+        assert_eq!(conda("0.4.1.rc"), conda("0.4.1.RC"));
+    }
+
+    #[test]
+    fn conda_constraints() {
+        assert!(conda_version_matches("3.8.*", "3.8.18").unwrap());
+        assert!(!conda_version_matches("3.8.*", "3.80.1").unwrap());
+        assert!(conda_version_matches(">=1.20,<2", "1.26.0").unwrap());
+        assert!(!conda_version_matches(">=1.20,<2", "2.0.1").unwrap());
+        assert!(conda_version_matches("1.20|1.26.0", "1.26.0").unwrap());
+        assert!(conda_version_matches("==1.26.0", "1.26").is_ok());
+        assert!(!conda_version_matches("!=1.26.*", "1.26.4").unwrap());
+        assert!(conda_version_matches("~=1.26.0", "1.26.4").unwrap());
+        assert!(!conda_version_matches("~=1.26.0", "1.27.0").unwrap());
+        assert!(conda_version_matches("*", "0.1").unwrap());
+        assert!(conda_version_matches("?1.0", "1.0").is_err());
+    }
+
+    #[test]
+    fn pip_version_ordering() {
+        let ordered = [
+            "1.0.dev456",
+            "1.0a1",
+            "1.0a2.dev456",
+            "1.0a12",
+            "1.0b1.dev456",
+            "1.0b2",
+            "1.0b2.post345",
+            "1.0rc1",
+            "1.0",
+            "1.0+abc.5",
+            "1.0.post456.dev34",
+            "1.0.post456",
+            "1.1.dev1",
+            "1!0.1",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(pip(pair[0]) < pip(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert_eq!(pip("1.0"), pip("1.0.0"));
+        assert_eq!(pip("1.0-1"), pip("1.0.post1"));
+        assert!(PipVersion::parse("1.0.foo").is_err());
+    }
+
+    #[test]
+    fn pip_constraints() {
+        assert!(pip_version_matches(">=2,<3", "2.31.0").unwrap());
+        assert!(!pip_version_matches(">=2,<3", "3.0").unwrap());
+        assert!(pip_version_matches("==2.31.*", "2.31.0").unwrap());
+        assert!(!pip_version_matches("==2.3.*", "2.31.0").unwrap());
+        assert!(pip_version_matches("~=2.28", "2.31.0").unwrap());
+        assert!(!pip_version_matches("~=2.28.0", "2.31.0").unwrap());
+        assert!(pip_version_matches("==1.0", "1.0+local").unwrap());
+        assert!(pip_version_matches("!=1.1", "1.0").unwrap());
+    }
+
+    #[test]
+    fn build_globs() {
+        assert!(glob_matches("py38*", "py38h64a7726_0"));
+        assert!(glob_matches("*_0", "py38h64a7726_0"));
+        assert!(glob_matches("py*h*_0", "py38h64a7726_0"));
+        assert!(!glob_matches("py39*", "py38h64a7726_0"));
+        assert!(glob_matches("py38h64a7726_0", "py38h64a7726_0"));
+    }
+}